@@ -0,0 +1,157 @@
+/// A command an operator can send the bot in a private message.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Stop replying to comments, mentions and messages until resumed.
+    Pause,
+    Resume,
+    /// Reply with how the bot is doing.
+    Status,
+    AddSubreddit(String),
+    RemoveSubreddit(String),
+    DryRun(bool),
+    /// List the replies waiting for review in supervised mode.
+    Review,
+    /// Deliver a reply waiting for review, by the comment's fullname.
+    Approve(String),
+    /// Discard a reply waiting for review and save the comment to the regression corpus with the
+    /// operator's reason. false_positive marks a comment the detector shouldn't have flagged.
+    Reject {
+        name: String,
+        false_positive: bool,
+        reason: String,
+    },
+}
+
+pub const HELP: &str = "Commands:\n\n\
+    * `pause`\n\
+    * `resume`\n\
+    * `status`\n\
+    * `add-subreddit <name>`\n\
+    * `remove-subreddit <name>`\n\
+    * `dry-run on` or `dry-run off`\n\
+    * `review`\n\
+    * `approve <comment fullname>`\n\
+    * `reject <comment fullname> [false-positive] [reason]`";
+
+impl Command {
+    /// Parse a message body. The command is the first line; the subject is ignored. Returns None
+    /// if it isn't a valid command.
+    pub fn parse(body: &str) -> Option<Command> {
+        let line = body.lines().next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let command = words.next()?.to_lowercase();
+        let arg = words.next();
+        let rest: Vec<&str> = words.collect();
+        // Only reject takes more than one argument.
+        if !rest.is_empty() && command != "reject" {
+            return None;
+        }
+        let subreddit = |arg: Option<&str>| {
+            let name = arg?;
+            let name = name
+                .trim_start_matches('/')
+                .trim_start_matches("r/")
+                .trim_end_matches('/');
+            if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                Some(name.to_string())
+            } else {
+                None
+            }
+        };
+        let fullname = |arg: Option<&str>| {
+            let name = arg?;
+            if name.starts_with("t1_") && crate::review::is_valid_name(name) {
+                Some(name.to_string())
+            } else {
+                None
+            }
+        };
+        match (command.as_str(), arg) {
+            ("pause", None) => Some(Command::Pause),
+            ("resume", None) => Some(Command::Resume),
+            ("status", None) => Some(Command::Status),
+            ("review", None) => Some(Command::Review),
+            ("approve", _) => fullname(arg).map(Command::Approve),
+            ("reject", _) => {
+                let false_positive = rest.first() == Some(&"false-positive");
+                let reason = rest[if false_positive { 1 } else { 0 }..].join(" ");
+                fullname(arg).map(|name| Command::Reject {
+                    name,
+                    false_positive,
+                    reason,
+                })
+            }
+            ("add-subreddit", _) => subreddit(arg).map(Command::AddSubreddit),
+            ("remove-subreddit", _) => subreddit(arg).map(Command::RemoveSubreddit),
+            ("dry-run", Some(arg)) => match arg.to_lowercase().as_str() {
+                "on" => Some(Command::DryRun(true)),
+                "off" => Some(Command::DryRun(false)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let tests: &[(&'static str, Option<Command>)] = &[
+            ("pause", Some(Command::Pause)),
+            ("  Resume \n\nthanks", Some(Command::Resume)),
+            ("status", Some(Command::Status)),
+            (
+                "add-subreddit r/learnrust",
+                Some(Command::AddSubreddit("learnrust".to_string())),
+            ),
+            (
+                "remove-subreddit /r/rust/",
+                Some(Command::RemoveSubreddit("rust".to_string())),
+            ),
+            ("add-subreddit", None),
+            ("add-subreddit rust+linux", None),
+            ("dry-run on", Some(Command::DryRun(true))),
+            ("dry-run OFF", Some(Command::DryRun(false))),
+            ("dry-run", None),
+            ("pause now", None),
+            ("", None),
+            ("review", Some(Command::Review)),
+            (
+                "approve t1_abc",
+                Some(Command::Approve("t1_abc".to_string())),
+            ),
+            (
+                "reject t1_abc",
+                Some(Command::Reject {
+                    name: "t1_abc".to_string(),
+                    false_positive: false,
+                    reason: "".to_string(),
+                }),
+            ),
+            (
+                "reject t1_abc false-positive single line block",
+                Some(Command::Reject {
+                    name: "t1_abc".to_string(),
+                    false_positive: true,
+                    reason: "single line block".to_string(),
+                }),
+            ),
+            (
+                "reject t1_abc rude reply",
+                Some(Command::Reject {
+                    name: "t1_abc".to_string(),
+                    false_positive: false,
+                    reason: "rude reply".to_string(),
+                }),
+            ),
+            ("reject ../state", None),
+            ("```\nfn main() {}\n```", None),
+        ];
+        for (body, want) in tests {
+            assert_eq!(*want, Command::parse(body), "{:?}", body);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+/// Runtime configuration. Every field has a default, so a config file only needs to mention the
+/// settings it changes.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where to get the reddit credentials.
+    pub credentials: crate::credentials::CredentialSource,
+    /// Subreddits to watch for new comments. They are all polled together in one listing.
+    pub subreddits: Vec<String>,
+    /// Comments older than this many seconds are ignored, and polling doesn't page back further.
+    pub max_age_secs: u64,
+    /// Where to save state that should survive restarts, such as where polling left off. If set to
+    /// null, nothing is saved and the bot doesn't post replies or deliveries, since it couldn't
+    /// tell after a crash whether it already had.
+    pub state_path: Option<String>,
+    /// How often to check the inbox for mentions, in seconds.
+    pub inbox_interval_secs: u64,
+    /// Users who asked the bot not to reply to them or check their comments.
+    pub opted_out_users: Vec<String>,
+    /// Users whose comments the bot never checks, e.g. other bots.
+    pub ignored_users: Vec<String>,
+    /// Other bots that point out code formatting problems. The bot doesn't reply to comments they
+    /// already replied to.
+    pub formatting_bots: Vec<String>,
+    /// Ignore users whose names end in "bot".
+    pub ignore_bot_names: bool,
+    /// Ignore distinguished comments, i.e. moderators and admins speaking officially.
+    pub ignore_distinguished: bool,
+    /// Ignore moderators in the subreddits they moderate.
+    pub exempt_moderators: bool,
+    /// How to tell authors about problems, by subreddit. Subreddits not listed use
+    /// default_delivery.
+    pub delivery: std::collections::HashMap<String, Delivery>,
+    pub default_delivery: Delivery,
+    /// Subreddits where the bot must not reply, even when summoned. See banned.md.
+    pub banned_subreddits: Vec<String>,
+    /// The minimum time between requests across all threads, in milliseconds.
+    pub request_interval_ms: u64,
+    /// If set, the problematic fenced blocks are converted to indented blocks and included in the
+    /// reply when they add at most this many bytes to it, counting the indentation.
+    pub inline_fix_max_len: Option<usize>,
+    /// How long to wait after a flagged comment was posted before replying, in seconds. The
+    /// comment is checked again first, so authors who fix it in time don't get a reply.
+    pub grace_period_secs: u64,
+    /// When to fetch comments again to see if an edit made them problematic, in seconds after
+    /// they were posted. Empty to never check edits.
+    pub revisit_intervals_secs: Vec<u64>,
+    /// Stop checking the parent of a reply once the reply is this many seconds old.
+    pub recheck_window_secs: u64,
+    /// What to do with a reply once its parent comment is deleted or removed.
+    pub orphan_action: OrphanAction,
+    /// Users who can control the bot by private message. See admin.rs for the commands.
+    pub operators: Vec<String>,
+    /// If true, don't create, edit or delete comments, except replies to operators. Operators can
+    /// toggle this while the bot runs.
+    pub dry_run: bool,
+    /// If set, the bot checks this while running and acts as in a dry run while it's set.
+    pub kill_switch: Option<KillSwitch>,
+    /// A lock file shared by several instances of the bot, e.g. on a network filesystem. Only the
+    /// instance holding it posts; the others keep polling and take over when the leader exits.
+    /// Every instance must use the same state_path on the same storage. Only the leader writes it,
+    /// and a standby loads it when it takes over. Only supported on unix.
+    pub leader_lock: Option<String>,
+    /// How long the leader keeps posting without confirming its lock, in seconds.
+    pub leader_lease_secs: u64,
+    /// If true, replies to new comments are queued for an operator to approve instead of being
+    /// delivered.
+    pub supervised: bool,
+    /// Where supervised mode queues replies, one JSON file per comment.
+    pub review_dir: String,
+    /// Where rejected replies' comments are saved as regression cases. See corpus/ in the
+    /// repository.
+    pub corpus_dir: String,
+    /// Limits on how many replies the bot posts.
+    pub rate_caps: crate::ratecap::RateCaps,
+    /// What to do with a comment when replying would exceed a rate cap.
+    pub over_cap_action: OverCapAction,
+}
+
+/// What to do with a reply whose parent comment is gone.
+#[derive(Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OrphanAction {
+    /// Delete the reply.
+    Delete,
+    /// Edit the reply to say the parent is gone.
+    MarkResolved,
+}
+
+/// How to tell an author about a problematic comment.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Delivery {
+    /// Reply publicly.
+    Reply,
+    /// Send the reply to the author in a private message.
+    Pm,
+    /// Report the comment to the subreddit's moderators.
+    Report,
+    /// Only log what the bot would have said.
+    LogOnly,
+}
+
+/// What to do with a comment that can't be replied to because of a rate cap.
+#[derive(Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverCapAction {
+    /// Don't reply to it.
+    Skip,
+    /// Reply once the caps allow it, if the comment isn't too old by then.
+    Queue,
+}
+
+/// Where to read the kill switch from.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "source", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum KillSwitch {
+    /// The switch is set while this file exists.
+    File { path: String },
+    /// The switch is set while this page on the bot's own wiki (r/u_<username>) isn't blank.
+    Wiki { page: String },
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            credentials: Default::default(),
+            subreddits: vec![
+                "programming".to_string(),
+                "rust".to_string(),
+                "NixOS".to_string(),
+            ],
+            max_age_secs: 60 * 60 * 24,
+            state_path: Some("state.json".to_string()),
+            inbox_interval_secs: 60,
+            opted_out_users: Vec::new(),
+            banned_subreddits: vec!["linux".to_string()],
+            delivery: Default::default(),
+            default_delivery: Delivery::Reply,
+            ignored_users: vec!["AutoModerator".to_string()],
+            formatting_bots: Vec::new(),
+            ignore_bot_names: true,
+            ignore_distinguished: true,
+            exempt_moderators: true,
+            request_interval_ms: 1000,
+            inline_fix_max_len: None,
+            grace_period_secs: 5 * 60,
+            revisit_intervals_secs: vec![10 * 60, 60 * 60, 6 * 60 * 60],
+            recheck_window_secs: 60 * 60 * 24 * 3,
+            orphan_action: OrphanAction::Delete,
+            operators: Vec::new(),
+            dry_run: false,
+            kill_switch: None,
+            leader_lock: None,
+            leader_lease_secs: 60,
+            supervised: false,
+            review_dir: "review".to_string(),
+            corpus_dir: "corpus".to_string(),
+            rate_caps: Default::default(),
+            over_cap_action: OverCapAction::Skip,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config from a JSON file.
+    pub fn load(path: &str) -> Result<Config, crate::error::Error> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(f))?)
+    }
+
+    /// How to tell authors in a subreddit about problems.
+    pub fn delivery_for(&self, subreddit: &str) -> Delivery {
+        self.delivery
+            .iter()
+            .find(|(s, _)| s.eq_ignore_ascii_case(subreddit))
+            .map_or(self.default_delivery, |(_, d)| *d)
+    }
+}
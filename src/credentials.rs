@@ -0,0 +1,249 @@
+use zeroize::Zeroizing;
+
+/// The secrets needed to log in. They are zeroized when dropped.
+pub struct Credentials {
+    pub id: Zeroizing<String>,
+    pub secret: Zeroizing<String>,
+    pub password: Zeroizing<String>,
+}
+
+/// One of the secrets in Credentials.
+#[derive(Clone, Copy, Debug)]
+pub enum Key {
+    Id,
+    Secret,
+    Password,
+}
+
+impl Key {
+    /// The name of the secret in sources that name each secret the same way.
+    fn name(self) -> &'static str {
+        match self {
+            Key::Id => "id",
+            Key::Secret => "secret",
+            Key::Password => "password",
+        }
+    }
+}
+
+#[derive(Debug, failure::Fail)]
+pub enum CredentialError {
+    #[fail(display = "could not run {}: {}", command, error)]
+    Command {
+        command: &'static str,
+        error: std::io::Error,
+    },
+    #[fail(display = "{} failed for {}: {}", command, name, stderr)]
+    CommandFailed {
+        command: &'static str,
+        name: String,
+        stderr: String,
+    },
+    #[fail(display = "environment variable {} is not set", _0)]
+    MissingEnv(String),
+    #[fail(display = "could not read {}: {}", path, error)]
+    Read { path: String, error: std::io::Error },
+    #[fail(
+        display = "{} is accessible by other users (mode {:o}); it should be 0600 or stricter",
+        path, mode
+    )]
+    Permissions { path: String, mode: u32 },
+    #[fail(display = "{} has no {:?} entry", path, key)]
+    MissingEntry { path: String, key: Key },
+    #[fail(
+        display = "$CREDENTIALS_DIRECTORY is not set; is the bot running under systemd with LoadCredential?"
+    )]
+    NoCredentialsDirectory,
+    #[fail(display = "{} is not valid UTF-8", _0)]
+    NotUtf8(String),
+    #[fail(display = "{} is empty", _0)]
+    Empty(String),
+}
+
+/// Where to get the credentials from.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "source", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum CredentialSource {
+    /// Entries in pass (password-store). The first line of each entry is used.
+    Pass {
+        id: String,
+        secret: String,
+        password: String,
+    },
+    /// The environment variables OLD_REDDIT_FMT_BOT_ID, OLD_REDDIT_FMT_BOT_SECRET and
+    /// OLD_REDDIT_FMT_BOT_PASSWORD. They are removed from the environment once read.
+    Env,
+    /// A file with `id=`, `secret=` and `password=` lines. It must not be accessible by other
+    /// users.
+    File { path: String },
+    /// Files named id, secret and password in $CREDENTIALS_DIRECTORY, as set up by systemd's
+    /// LoadCredential= or SetCredential=.
+    Systemd,
+}
+
+impl Default for CredentialSource {
+    fn default() -> CredentialSource {
+        CredentialSource::Pass {
+            id: "Reddit/old-reddit-fmt-bot/id".to_string(),
+            secret: "Reddit/old-reddit-fmt-bot/secret".to_string(),
+            password: "Misc/reddit.com/old-reddit-fmt-bot".to_string(),
+        }
+    }
+}
+
+impl CredentialSource {
+    /// Load all the credentials.
+    pub fn load(&self) -> Result<Credentials, CredentialError> {
+        Ok(Credentials {
+            id: self.get(Key::Id)?,
+            secret: self.get(Key::Secret)?,
+            password: self.get(Key::Password)?,
+        })
+    }
+
+    /// Load one secret.
+    pub fn get(&self, key: Key) -> Result<Zeroizing<String>, CredentialError> {
+        let value = match self {
+            CredentialSource::Pass {
+                id,
+                secret,
+                password,
+            } => {
+                let name = match key {
+                    Key::Id => id,
+                    Key::Secret => secret,
+                    Key::Password => password,
+                };
+                get_pass(name)?
+            }
+            CredentialSource::Env => {
+                let var = format!("OLD_REDDIT_FMT_BOT_{}", key.name().to_uppercase());
+                let value = match std::env::var(&var) {
+                    Ok(x) => Zeroizing::new(x),
+                    Err(std::env::VarError::NotPresent) => {
+                        return Err(CredentialError::MissingEnv(var))
+                    }
+                    Err(std::env::VarError::NotUnicode(_)) => {
+                        return Err(CredentialError::NotUtf8(var))
+                    }
+                };
+                // Child processes don't need it.
+                std::env::remove_var(&var);
+                value
+            }
+            CredentialSource::File { path } => {
+                let contents = read_private_file(path)?;
+                let prefix = format!("{}=", key.name());
+                match contents.lines().find(|l| l.starts_with(&prefix)) {
+                    Some(line) => Zeroizing::new(line[prefix.len()..].to_string()),
+                    None => {
+                        return Err(CredentialError::MissingEntry {
+                            path: path.clone(),
+                            key,
+                        })
+                    }
+                }
+            }
+            CredentialSource::Systemd => {
+                let dir = std::env::var_os("CREDENTIALS_DIRECTORY")
+                    .ok_or(CredentialError::NoCredentialsDirectory)?;
+                let path = std::path::Path::new(&dir).join(key.name());
+                let contents = read_file(&path.to_string_lossy())?;
+                Zeroizing::new(contents.trim_end_matches('\n').to_string())
+            }
+        };
+        if value.is_empty() {
+            return Err(CredentialError::Empty(format!("{:?}", key)));
+        }
+        Ok(value)
+    }
+}
+
+/// Retrieve a password from pass (password-store).
+fn get_pass(p: &str) -> Result<Zeroizing<String>, CredentialError> {
+    let res = std::process::Command::new("pass")
+        .arg("show")
+        .arg("--")
+        .arg(p)
+        .output()
+        .map_err(|error| CredentialError::Command {
+            command: "pass",
+            error,
+        })?;
+    let stdout = Zeroizing::new(res.stdout);
+    if !res.status.success() {
+        return Err(CredentialError::CommandFailed {
+            command: "pass",
+            name: p.to_string(),
+            stderr: String::from_utf8_lossy(&res.stderr).trim().to_string(),
+        });
+    }
+    let stdout =
+        std::str::from_utf8(&stdout).map_err(|_| CredentialError::NotUtf8(p.to_string()))?;
+    Ok(Zeroizing::new(
+        stdout.lines().next().unwrap_or("").to_string(),
+    ))
+}
+
+fn read_file(path: &str) -> Result<Zeroizing<String>, CredentialError> {
+    let bytes = Zeroizing::new(std::fs::read(path).map_err(|error| CredentialError::Read {
+        path: path.to_string(),
+        error,
+    })?);
+    match std::str::from_utf8(&bytes) {
+        Ok(s) => Ok(Zeroizing::new(s.to_string())),
+        Err(_) => Err(CredentialError::NotUtf8(path.to_string())),
+    }
+}
+
+/// Read a file after checking that other users can't access it.
+fn read_private_file(path: &str) -> Result<Zeroizing<String>, CredentialError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let meta = std::fs::metadata(path).map_err(|error| CredentialError::Read {
+            path: path.to_string(),
+            error,
+        })?;
+        let mode = meta.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            return Err(CredentialError::Permissions {
+                path: path.to_string(),
+                mode,
+            });
+        }
+    }
+    read_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_source() {
+        use std::os::unix::fs::PermissionsExt;
+        let path =
+            std::env::temp_dir().join(format!("old-reddit-fmt-bot-test-{}", std::process::id()));
+        std::fs::write(&path, "id=i\nsecret=s=s\npassword=p\n").unwrap();
+        let source = CredentialSource::File {
+            path: path.to_string_lossy().into_owned(),
+        };
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        match source.load() {
+            Err(CredentialError::Permissions { mode: 0o644, .. }) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected a permissions error"),
+        }
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let credentials = source.load().unwrap();
+        assert_eq!("i", credentials.id.as_str());
+        assert_eq!("s=s", credentials.secret.as_str());
+        assert_eq!("p", credentials.password.as_str());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
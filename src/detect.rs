@@ -0,0 +1,444 @@
+/// Turn a comment or message body from the API into the markdown its author wrote. Every code
+/// path that reads comment text should use this so verdicts agree.
+///
+/// Reddit entity encodes bodies for legacy reasons unless the client passes raw_json=1, which orca
+/// doesn't. The new reddit editor also inserts zero-width spaces (`&#x200B;`) to keep empty
+/// paragraphs, which readers never see. They are entities in the markdown, so the API sends them
+/// as `&amp;#x200B;`, which decodes to the entity text. A body that isn't valid entity encoded
+/// text is used as is.
+pub fn normalize_body(body: &str) -> String {
+    let decoded = match htmlescape::decode_html(body) {
+        Ok(x) => x,
+        Err(e) => {
+            log::debug!("Using body as is, error decoding it: {:?}", e);
+            body.to_string()
+        }
+    };
+    decoded.replace("&#x200B;", "").replace('\u{200B}', "")
+}
+
+/// Options matching the markdown extensions new reddit supports. Reddit's superscript (`^word`)
+/// differs from comrak's (`^word^`), but both are inline and can't change block structure.
+pub fn comrak_opts() -> comrak::ComrakOptions {
+    comrak::ComrakOptions {
+        ext_strikethrough: true,
+        ext_table: true,
+        ext_autolink: true,
+        ext_superscript: true,
+        ..comrak::ComrakOptions::default()
+    }
+}
+
+/// Parse a body the way new reddit does, as closely as comrak allows.
+pub fn parse<'a>(
+    arena: &'a comrak::Arena<comrak::nodes::AstNode<'a>>,
+    body: &str,
+) -> &'a comrak::nodes::AstNode<'a> {
+    comrak::parse_document(arena, &escape_spoilers(body), &comrak_opts())
+}
+
+/// Escape reddit spoilers (`>!text!<`) at the start of a line, including inside block quotes,
+/// so comrak doesn't parse them as block quotes. Lines in code blocks are left alone since their
+/// text is literal. Characters are only inserted within lines, so line numbers in the AST still
+/// match the original body.
+fn escape_spoilers(body: &str) -> String {
+    let code_lines = code_lines(body);
+    let lines: Vec<String> = body
+        .split('\n')
+        .enumerate()
+        .map(|(line_idx, line)| {
+            if code_lines.contains(&line_idx) {
+                return line.to_string();
+            }
+            for (idx, c) in line.char_indices() {
+                if c == '>' && line[idx + 1..].starts_with('!') {
+                    return format!("{}\\{}", &line[..idx], &line[idx..]);
+                }
+                if c != '>' && !c.is_whitespace() {
+                    break;
+                }
+            }
+            line.to_string()
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// The indexes of the lines in code blocks, including fences.
+fn code_lines(body: &str) -> std::collections::HashSet<usize> {
+    let arena = comrak::Arena::new();
+    let ast = comrak::parse_document(&arena, body, &comrak_opts());
+    let mut lines = std::collections::HashSet::new();
+    for node in ast.descendants() {
+        let data = node.data.borrow();
+        if let comrak::nodes::NodeValue::CodeBlock(ref block) = data.value {
+            let start = data.start_line as usize - 1;
+            let n = String::from_utf8_lossy(&block.literal).lines().count();
+            // The closing fence may be missing, but then the block runs to the end anyway.
+            let n = if block.fenced { n + 2 } else { n };
+            lines.extend(start..start + n);
+        }
+    }
+    lines
+}
+
+/// Why a fenced code block won't render well in old reddit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockProblem {
+    InfoString,
+    Empty,
+    Unterminated,
+    MultipleLines,
+    Whitespace,
+    NoBlankLineBefore,
+}
+
+impl BlockProblem {
+    /// Describe the problem to the author of the block.
+    pub fn describe(self) -> &'static str {
+        match self {
+            BlockProblem::InfoString => {
+                "has a language after the opening fence (e.g. ```` ```rust ````), which old reddit \
+                 shows as part of the code"
+            }
+            BlockProblem::Empty => "is empty",
+            BlockProblem::Unterminated => "doesn't end with a line break",
+            BlockProblem::MultipleLines => {
+                "has more than one line, which old reddit joins together"
+            }
+            BlockProblem::Whitespace => {
+                "has tabs or runs of spaces, which old reddit collapses into single spaces"
+            }
+            BlockProblem::NoBlankLineBefore => {
+                "doesn't have a blank line before it, so old reddit joins it with the previous \
+                 paragraph"
+            }
+        }
+    }
+}
+
+/// A fenced code block that won't render well in old reddit.
+pub struct ProblemBlock {
+    pub problem: BlockProblem,
+    pub literal: String,
+}
+
+/// Check if a node is a fenced code block that won't render well in old reddit, and why.
+pub fn fenced_block_problem<'a>(node: &'a comrak::nodes::AstNode<'a>) -> Option<BlockProblem> {
+    lazy_static::lazy_static! {
+        static ref BAD_CONTENTS: regex::bytes::Regex = regex::bytes::Regex::new(r"\t|  |^ | \n").unwrap();
+    }
+    let n = node.data.borrow();
+    match n.value {
+        comrak::nodes::NodeValue::CodeBlock(ref block) => {
+            if !block.fenced {
+                return None;
+            }
+            // Some single line fenced blocks look OK. See
+            // https://github.com/singron/old-reddit-fmt-bot/issues/1
+            if !block.info.is_empty() {
+                return Some(BlockProblem::InfoString);
+            }
+            let b: &[u8] = &block.literal;
+            if b.is_empty() {
+                return Some(BlockProblem::Empty);
+            }
+            if b.get(b.len() - 1) != Some(&b'\n') {
+                return Some(BlockProblem::Unterminated);
+            }
+            if b.iter().filter(|c| **c == b'\n').count() != 1 {
+                return Some(BlockProblem::MultipleLines);
+            }
+            if BAD_CONTENTS.is_match(b) {
+                return Some(BlockProblem::Whitespace);
+            }
+
+            if let Some(prev) = node.previous_sibling() {
+                if !prev.data.borrow().last_line_blank {
+                    return Some(BlockProblem::NoBlankLineBefore);
+                }
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Check if this reddit markdown body contains fenced code blocks that won't render well in old
+/// reddit.
+pub fn contains_problematic_fenced_block(body: &str) -> bool {
+    let arena = comrak::Arena::new();
+    let ast = parse(&arena, body);
+    ast.descendants()
+        .any(|node| fenced_block_problem(node).is_some())
+}
+
+/// Return each fenced code block in this body that won't render well in old reddit.
+pub fn problematic_fenced_blocks(body: &str) -> Vec<ProblemBlock> {
+    let arena = comrak::Arena::new();
+    let ast = parse(&arena, body);
+    let mut blocks = Vec::new();
+    for node in ast.descendants() {
+        let problem = match fenced_block_problem(node) {
+            Some(x) => x,
+            None => continue,
+        };
+        if let comrak::nodes::NodeValue::CodeBlock(ref block) = node.data.borrow().value {
+            blocks.push(ProblemBlock {
+                problem,
+                literal: String::from_utf8_lossy(&block.literal).into_owned(),
+            });
+        }
+    }
+    blocks
+}
+
+/// The part of a block's continuation prefix that a line must repeat to stay in the same
+/// containers as the block.
+fn container_prefix<'a>(prefix: &str, continuation: &'a str) -> &'a str {
+    match prefix.trim_end().chars().last() {
+        // No containers, or a block quote, whose `>` may or may not be followed by a space.
+        None | Some('>') => continuation.trim_end(),
+        // A list item's content must stay indented past its marker.
+        Some(_) => continuation,
+    }
+}
+
+/// Convert each fenced code block in this body that won't render well in old reddit into an
+/// indented code block. Returns the converted body and the problems that were fixed.
+///
+/// Only the lines of the converted blocks are changed, so the rest of the markdown stays exactly as
+/// the author wrote it.
+pub fn convert_fenced_blocks(body: &str) -> (String, Vec<BlockProblem>) {
+    let arena = comrak::Arena::new();
+    let ast = parse(&arena, body);
+    let lines: Vec<&str> = body.lines().collect();
+    // (first line, end line, replacement lines) for each block, in order.
+    let mut replacements: Vec<(usize, usize, Vec<String>)> = Vec::new();
+    let mut problems = Vec::new();
+    for node in ast.descendants() {
+        let problem = match fenced_block_problem(node) {
+            Some(x) => x,
+            None => continue,
+        };
+        let data = node.data.borrow();
+        let block = match data.value {
+            comrak::nodes::NodeValue::CodeBlock(ref block) => block,
+            _ => continue,
+        };
+        let start = data.start_line as usize - 1;
+        let open_line = match lines.get(start) {
+            Some(x) => *x,
+            None => continue,
+        };
+        let fence: String = std::iter::repeat(block.fence_char as char)
+            .take(block.fence_length)
+            .collect();
+        // The prefix holds the containers the block is in, e.g. "> " or "1.  ". Following lines
+        // continue the containers without repeating list markers.
+        let prefix = match open_line.find(&fence) {
+            Some(x) => &open_line[..x],
+            None => continue,
+        };
+        let continuation: String = prefix
+            .chars()
+            .map(|c| {
+                if c == '>' || c.is_whitespace() {
+                    c
+                } else {
+                    ' '
+                }
+            })
+            .collect();
+        let literal = String::from_utf8_lossy(&block.literal);
+        let mut new_lines = Vec::new();
+        // Indented code blocks can't interrupt a paragraph.
+        let after_text = start > 0
+            && !lines[start - 1]
+                .trim_start_matches(|c: char| c == '>' || c.is_whitespace())
+                .is_empty();
+        if after_text {
+            new_lines.push(continuation.trim_end().to_string());
+        }
+        for (idx, line) in literal.lines().enumerate() {
+            let p = if idx == 0 { prefix } else { &continuation };
+            new_lines.push(format!("{}    {}", p, line));
+        }
+        let mut end = start + 1 + literal.lines().count();
+        // A fence only closes the block from inside the same containers. A bare fence after a
+        // quoted block opens a new block instead.
+        let container = container_prefix(prefix, &continuation);
+        let closed = lines.get(end).map_or(false, |l| {
+            l.starts_with(container) && l[container.len()..].trim_start().starts_with(&fence)
+        });
+        if closed {
+            end += 1;
+        }
+        // Blocks never overlap, but don't trust the line arithmetic above with user input.
+        if replacements.last().map_or(false, |r| start < r.1) {
+            continue;
+        }
+        replacements.push((start, end.min(lines.len()), new_lines));
+        problems.push(problem);
+    }
+    let mut out: Vec<String> = Vec::with_capacity(lines.len());
+    let mut next = 0;
+    for (start, end, new_lines) in replacements {
+        out.extend(lines[next..start].iter().map(|l| l.to_string()));
+        out.extend(new_lines);
+        next = end;
+    }
+    out.extend(lines[next..].iter().map(|l| l.to_string()));
+    (out.join("\n"), problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_body() {
+        assert_eq!("```\n&\n&\n```", normalize_body("```\n&amp;\n&amp;\n```"));
+        assert_eq!(
+            "x\n\n\n\n```\nx\n```",
+            normalize_body("x\n\n&amp;#x200B;\n\n```\nx\n```")
+        );
+        // Already decoded text is left alone.
+        assert_eq!("a & b", normalize_body("a & b"));
+    }
+
+    /// Comments an operator decided the bot shouldn't have replied to. See review.rs.
+    #[test]
+    fn test_corpus_negative() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus/negative");
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |e| e != "json") {
+                continue;
+            }
+            let case: crate::review::CorpusCase =
+                serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+            if !case.false_positive {
+                continue;
+            }
+            assert!(
+                !contains_problematic_fenced_block(&case.body),
+                "{} is flagged ({})",
+                path.display(),
+                case.reason
+            );
+        }
+    }
+
+    #[test]
+    fn test_escape_spoilers() {
+        assert_eq!(
+            "\\>!a!<\n> \\>!b!<\n> quote\nx >!c!<",
+            escape_spoilers(">!a!<\n> >!b!<\n> quote\nx >!c!<")
+        );
+        // Code is literal.
+        let body = "```\n>!a\n>!b\n```\n\n    >!c\n\n>!d!<";
+        assert_eq!(
+            "```\n>!a\n>!b\n```\n\n    >!c\n\n\\>!d!<",
+            escape_spoilers(body)
+        );
+        assert_eq!(">!a\n>!b\n", problematic_fenced_blocks(body)[0].literal);
+    }
+
+    #[test]
+    fn test_fenced_block() {
+        let tests: &[(bool, &'static str)] = &[
+            (false, ""),
+            (false, "hi\n"),
+            (false, "inline `codeblock`\n"),
+            (false, "`code`\n"),
+            (false, "    hi\n"),
+            (false, ">     hi\n"),
+            // Some single line code blocks look close enough when interpreted as an inline code
+            // span.
+            (false, "```\nhi\n```"),
+            (false, "```\nx x\n```"),
+            (false, "hi\n\n```\nhi\n```\n\nhi"),
+            (false, "1.  ```\n    hi\n    ```"),
+            (false, "1.  ```\n    hi\n    ```\n\n    hi"),
+            (false, "> ```\n> hi\n> ```"),
+            (false, "```\nx\n```\n\n```\nx\n```"),
+            (true, "x\n```\nhi\n```"),
+            (true, "```rust\nhi\n```"),
+            (true, "> ```\n> hi\n> hi\n> ```"),
+            (true, "1.  hi\n    ```\n    hi\n    ```\n"),
+            (true, "```\n&\n&\n```"),
+            (true, "```\n\tx\n```"),
+            (true, "```\n x\n```"),
+            (true, "```\nx \n```"),
+            (true, "```\nx\tx\n```"),
+            (true, "```\nx  x\n```"),
+            (true, "```\nx\n```\n\n```\nx  x\n```"),
+            // New reddit extensions around fenced blocks.
+            (false, "a | b\n--|--\n1 | 2\n\n```\nhi\n```"),
+            (true, "a | b\n--|--\n1 | 2\n\n```\nx\nx\n```"),
+            (false, "~~old~~ new\n\n```\nhi\n```\n\n~~old~~ new"),
+            (true, "~~old~~ new\n```\nhi\n```"),
+            (true, "~~old~~\n\n```\nx\nx\n```\n\na | b\n--|--\n1 | 2"),
+            (false, ">!spoiler!<\n\n```\nhi\n```"),
+            (true, "```\nx  x\n```\n\n```\nx\n```"),
+        ];
+        for (want, body) in tests {
+            let got = contains_problematic_fenced_block(body);
+            if *want != got {
+                let arena = comrak::Arena::new();
+                let ast = parse(&arena, body);
+                println!("AST: {:#?}", ast);
+                panic!(
+                    "Expected {:?} for contains_problematic_fenced_block({:?})",
+                    *want, body
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_convert_fenced_blocks() {
+        use BlockProblem::*;
+        let tests: &[(&'static str, &'static str, &[BlockProblem])] = &[
+            ("hi\n\n```\nhi\n```\n\nhi", "hi\n\n```\nhi\n```\n\nhi", &[]),
+            ("x\n```\nhi\n```\ny", "x\n\n    hi\ny", &[NoBlankLineBefore]),
+            (
+                "> ```rust\n> a\n> b\n> ```",
+                ">     a\n>     b",
+                &[InfoString],
+            ),
+            (
+                "1.  hi\n    ```\n    x  x\n    ```\n",
+                "1.  hi\n\n        x  x",
+                &[Whitespace],
+            ),
+            (
+                "a\n\n```\nb\nc\n```\n\n```\nd\n```\n\n```\ne  e\n```",
+                "a\n\n    b\n    c\n\n```\nd\n```\n\n    e  e",
+                &[MultipleLines, Whitespace],
+            ),
+            // A bare fence doesn't close a quoted block. It opens a new block.
+            (
+                "> ```\n> a\n> b\n```",
+                ">     a\n>     b\n",
+                &[MultipleLines, Empty],
+            ),
+            (
+                "1.  ```\n    a\n    b\n```",
+                "1.      a\n        b\n",
+                &[MultipleLines, Empty],
+            ),
+        ];
+        for (body, want, want_problems) in tests {
+            let (got, problems) = convert_fenced_blocks(body);
+            assert_eq!(*want, got, "convert_fenced_blocks({:?})", body);
+            assert_eq!(
+                *want_problems,
+                &problems[..],
+                "convert_fenced_blocks({:?})",
+                body
+            );
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::time::Duration;
+
+/// Errors from talking to reddit or reading local files, classified by how the caller should
+/// react to them.
+#[derive(Debug, failure::Fail)]
+pub enum Error {
+    /// The request didn't get a usable response, e.g. a connection failure, a timeout or a 5xx.
+    #[fail(display = "network error: {}", _0)]
+    Network(failure::Error),
+    /// Our credentials were rejected.
+    #[fail(display = "authentication failed: {}", _0)]
+    Auth(failure::Error),
+    /// Reddit asked us to slow down. wait is how long it asked us to wait, if it said.
+    #[fail(display = "rate limited (wait {:?}): {}", wait, error)]
+    RateLimit {
+        wait: Option<Duration>,
+        error: failure::Error,
+    },
+    /// The thing doesn't exist (anymore).
+    #[fail(display = "not found: {}", _0)]
+    NotFound(failure::Error),
+    /// We aren't allowed to do this, e.g. because the bot is banned from the subreddit or the
+    /// thread is locked or archived.
+    #[fail(display = "forbidden: {}", _0)]
+    Forbidden(failure::Error),
+    /// A response or file couldn't be parsed.
+    #[fail(display = "parse error: {}", _0)]
+    Parse(failure::Error),
+    /// A local file couldn't be read or written.
+    #[fail(display = "{}", _0)]
+    Io(std::io::Error),
+}
+
+/// Codes in json.errors for things that don't exist (anymore).
+const NOT_FOUND_CODES: &[&str] = &[
+    "DELETED_COMMENT",
+    "DELETED_LINK",
+    "SUBREDDIT_NOEXIST",
+    "USER_DOESNT_EXIST",
+];
+
+/// Codes in json.errors for things we aren't allowed to do.
+const FORBIDDEN_CODES: &[&str] = &[
+    "THREAD_LOCKED",
+    "TOO_OLD",
+    "SUBREDDIT_NOTALLOWED",
+    "NOT_WHITELISTED_BY_USER_MESSAGE",
+];
+
+/// What a caller should do about an error.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Wait and try again.
+    Retry,
+    /// Give up on this item and continue with the next one.
+    Skip,
+    /// Stop the bot. Retrying can't help and continuing could make things worse.
+    Abort,
+}
+
+impl Error {
+    /// Classify an error returned by orca.
+    ///
+    /// The HTTP status decides when reddit answered with an error status. Reddit also reports
+    /// some errors in successful responses as JSON, e.g. {"json": {"errors": [["RATELIMIT", ...]]}},
+    /// and only those are recognized by their codes.
+    pub fn from_reddit(error: failure::Error) -> Error {
+        let status = error.iter_chain().find_map(|cause| {
+            match cause.downcast_ref::<orca::errors::RedditError>()? {
+                orca::errors::RedditError::BadStatus { status, .. } => Some(status.as_u16()),
+                _ => None,
+            }
+        });
+        if let Some(status) = status {
+            return Error::from_status(status, error);
+        }
+        if error.iter_chain().any(|cause| {
+            cause.downcast_ref::<hyper::Error>().is_some()
+                || cause.downcast_ref::<std::io::Error>().is_some()
+        }) {
+            return Error::Network(error);
+        }
+        if error
+            .iter_chain()
+            .any(|cause| cause.downcast_ref::<serde_json::Error>().is_some())
+        {
+            return Error::Parse(error);
+        }
+        let msg = error.to_string();
+        if msg.contains("RATELIMIT") {
+            return Error::RateLimit {
+                wait: parse_ratelimit_wait(&msg),
+                error,
+            };
+        }
+        if NOT_FOUND_CODES.iter().any(|code| msg.contains(code)) {
+            return Error::NotFound(error);
+        }
+        if FORBIDDEN_CODES.iter().any(|code| msg.contains(code)) {
+            return Error::Forbidden(error);
+        }
+        Error::Network(error)
+    }
+
+    /// Classify an error response by its HTTP status.
+    pub fn from_status(status: u16, error: failure::Error) -> Error {
+        match status {
+            401 => Error::Auth(error),
+            403 => Error::Forbidden(error),
+            404 => Error::NotFound(error),
+            429 => Error::RateLimit { wait: None, error },
+            _ => Error::Network(error),
+        }
+    }
+
+    pub fn action(&self) -> Action {
+        match self {
+            Error::Network(_) | Error::RateLimit { .. } => Action::Retry,
+            Error::NotFound(_) | Error::Forbidden(_) | Error::Parse(_) => Action::Skip,
+            Error::Auth(_) | Error::Io(_) => Action::Abort,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        if e.is_io() {
+            Error::Io(e.into())
+        } else {
+            Error::Parse(e.into())
+        }
+    }
+}
+
+/// Parse the wait out of reddit's rate limit message, e.g. "Take a break for 7 minutes before
+/// trying again." or the older "you are doing that too much. try again in 7 minutes."
+pub fn parse_ratelimit_wait(msg: &str) -> Option<Duration> {
+    lazy_static::lazy_static! {
+        static ref WAIT: regex::Regex =
+            regex::Regex::new(r"(?i)(?:try again in|take a break for) (\d+) (second|minute|hour)s?").unwrap();
+    }
+    let caps = WAIT.captures(msg)?;
+    let n: u64 = caps[1].parse().ok()?;
+    let unit = match caps[2].to_lowercase().as_str() {
+        "second" => 1,
+        "minute" => 60,
+        _ => 60 * 60,
+    };
+    Some(Duration::from_secs(n * unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ratelimit_wait() {
+        let tests: &[(&'static str, Option<u64>)] = &[
+            (
+                "RATELIMIT: you are doing that too much. try again in 7 minutes.",
+                Some(7 * 60),
+            ),
+            (
+                "you are doing that too much. try again in 1 minute.",
+                Some(60),
+            ),
+            ("Try again in 30 seconds", Some(30)),
+            (
+                "RATELIMIT: Looks like you've been doing that a lot. Take a break for 5 minutes \
+                 before trying again.",
+                Some(5 * 60),
+            ),
+            ("Take a break for 1 minute before trying again.", Some(60)),
+            ("try again in 2 hours", Some(2 * 60 * 60)),
+            ("you are doing that too much", None),
+        ];
+        for (msg, want) in tests {
+            assert_eq!(
+                want.map(Duration::from_secs),
+                parse_ratelimit_wait(msg),
+                "{}",
+                msg
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_reddit() {
+        let e = Error::from_reddit(failure::err_msg(
+            "RATELIMIT: you are doing that too much. try again in 7 minutes.",
+        ));
+        match e {
+            Error::RateLimit { wait, .. } => assert_eq!(Some(Duration::from_secs(7 * 60)), wait),
+            e => panic!("{:?}", e),
+        }
+        // Errors from orca with the status of reddit's response.
+        let tests: &[(hyper::StatusCode, Action)] = &[
+            (hyper::StatusCode::UNAUTHORIZED, Action::Abort),
+            (hyper::StatusCode::FORBIDDEN, Action::Skip),
+            (hyper::StatusCode::NOT_FOUND, Action::Skip),
+            (hyper::StatusCode::TOO_MANY_REQUESTS, Action::Retry),
+            (hyper::StatusCode::SERVICE_UNAVAILABLE, Action::Retry),
+        ];
+        for (status, want) in tests {
+            let e = Error::from_reddit(
+                orca::errors::RedditError::BadStatus {
+                    status: *status,
+                    body: "{}".to_string(),
+                }
+                .into(),
+            );
+            assert_eq!(*want, e.action(), "{}", status);
+        }
+        let e = Error::from_reddit(
+            orca::errors::RedditError::BadStatus {
+                status: hyper::StatusCode::NOT_FOUND,
+                body: "{}".to_string(),
+            }
+            .into(),
+        );
+        match e {
+            Error::NotFound(_) => (),
+            e => panic!("{:?}", e),
+        }
+        // Status codes in the text, e.g. in ids, don't count.
+        let e = Error::from_reddit(
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "connection reset by peer: https://oauth.reddit.com/r/rust/comments/d401ab/",
+            )
+            .into(),
+        );
+        match e {
+            Error::Network(_) => (),
+            e => panic!("{:?}", e),
+        }
+        let tests: &[&'static str] = &[
+            "error sending request for url (https://oauth.reddit.com/api/info?id=t1_f403zz)",
+            "404 Not Found",
+        ];
+        for msg in tests {
+            let e = Error::from_reddit(failure::err_msg(*msg));
+            assert_eq!(Action::Retry, e.action(), "{}", msg);
+        }
+        // Errors reddit reports in json.errors.
+        let tests: &[&'static str] = &[
+            "DELETED_COMMENT: that comment has been deleted",
+            "SUBREDDIT_NOEXIST: that subreddit doesn't exist",
+        ];
+        for msg in tests {
+            match Error::from_reddit(failure::err_msg(*msg)) {
+                Error::NotFound(_) => (),
+                e => panic!("{}: {:?}", msg, e),
+            }
+        }
+        let tests: &[&'static str] = &[
+            "THREAD_LOCKED: Comments are locked.",
+            "TOO_OLD: that's a piece of history now; it's too late to reply to it",
+            "SUBREDDIT_NOTALLOWED: you aren't allowed to post there.",
+        ];
+        for msg in tests {
+            match Error::from_reddit(failure::err_msg(*msg)) {
+                Error::Forbidden(_) => (),
+                e => panic!("{}: {:?}", msg, e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_status() {
+        let tests: &[(u16, Action)] = &[
+            (401, Action::Abort),
+            (403, Action::Skip),
+            (404, Action::Skip),
+            (429, Action::Retry),
+            (500, Action::Retry),
+            (503, Action::Retry),
+        ];
+        for (status, want) in tests {
+            let e = Error::from_status(*status, failure::err_msg("error"));
+            assert_eq!(*want, e.action(), "{}", status);
+        }
+    }
+}
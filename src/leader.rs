@@ -0,0 +1,179 @@
+use std::fs::File;
+#[cfg(unix)]
+use std::os::unix::fs::{FileExt, MetadataExt};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use crate::now_utc;
+
+/// Leader elects one of several bot instances to post, using an exclusive flock on a file on
+/// storage they share. The lock is released when the leader exits, and another instance takes
+/// over. The leader also writes its id and a heartbeat to the file for anyone looking.
+pub struct Leader {
+    path: String,
+    id: String,
+    lease: Duration,
+    last_check: Option<Instant>,
+    /// The locked file, while this instance holds the lock.
+    file: Option<File>,
+    /// When the lock was last confirmed.
+    renewed: Option<Instant>,
+}
+
+impl Leader {
+    pub fn new(path: String, lease: Duration) -> Leader {
+        let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|s| s.trim().to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        Leader {
+            path,
+            id: format!("{}-{}-{:x}", host, std::process::id(), now_utc().to_bits()),
+            lease,
+            last_check: None,
+            file: None,
+            renewed: None,
+        }
+    }
+
+    /// Check if this instance leads. It stops leading once the lock hasn't been confirmed for
+    /// the lease, e.g. because the shared storage is unreachable.
+    pub fn is_leader(&self) -> bool {
+        self.file.is_some() && self.renewed.map_or(false, |r| r.elapsed() < self.lease)
+    }
+
+    /// How often to renew the heartbeat or check whether the leader is gone.
+    pub fn interval(&self) -> Duration {
+        self.lease / 3
+    }
+
+    /// Renew the heartbeat if this instance leads, or take over if the lock is free. Does nothing
+    /// if the last check was recent. Returns whether this instance leads.
+    pub fn check(&mut self) -> bool {
+        if let Some(last) = self.last_check {
+            if last.elapsed() < self.interval() {
+                return self.is_leader();
+            }
+        }
+        self.confirm()
+    }
+
+    /// Check the lock now, regardless of the last check. Call this right before posting.
+    pub fn confirm(&mut self) -> bool {
+        self.last_check = Some(Instant::now());
+        let was_leader = self.is_leader();
+        let start = Instant::now();
+        match self.try_lead() {
+            Ok(true) => self.renewed = Some(start),
+            Ok(false) => self.renewed = None,
+            Err(e) => {
+                log::error!("Error checking leader lock {:?}: {}", self.path, e);
+                // Without the lock file, the safe choice is to not post.
+                self.renewed = None;
+            }
+        }
+        let is_leader = self.is_leader();
+        if is_leader != was_leader {
+            if is_leader {
+                log::warn!("Became the leader as {}", self.id);
+            } else {
+                log::error!("No longer the leader; standing by");
+            }
+        }
+        is_leader
+    }
+
+    #[cfg(unix)]
+    fn try_lead(&mut self) -> std::io::Result<bool> {
+        if self.file.is_none() {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.path)?;
+            if !lock_exclusive(&file)? {
+                return Ok(false);
+            }
+            self.file = Some(file);
+        }
+        let file = self.file.as_ref().unwrap();
+        // If the file was deleted or replaced, another instance can lock the new one, so this
+        // lock no longer counts.
+        let same_file = match std::fs::metadata(&self.path) {
+            Ok(m) => {
+                let locked = file.metadata()?;
+                m.dev() == locked.dev() && m.ino() == locked.ino()
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        if !same_file {
+            self.file = None;
+            return Ok(false);
+        }
+        // Writing also fails if the storage went away.
+        file.set_len(0)?;
+        file.write_all_at(format!("{} {}\n", self.id, now_utc()).as_bytes(), 0)?;
+        Ok(true)
+    }
+
+    /// Other platforms lack flock, so main refuses a leader_lock there.
+    #[cfg(not(unix))]
+    fn try_lead(&mut self) -> std::io::Result<bool> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "leader locks are only supported on unix",
+        ))
+    }
+}
+
+/// Try to take an exclusive lock on a file without waiting. Returns false if another open file
+/// holds it.
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> std::io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    if e.kind() == std::io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_leader() {
+        let path = std::env::temp_dir()
+            .join(format!("old-reddit-fmt-bot-leader-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let mut a = Leader::new(path.clone(), Duration::from_secs(60));
+        let mut b = Leader::new(path.clone(), Duration::from_secs(60));
+        assert!(a.check());
+        assert!(!b.check());
+        assert!(!b.confirm());
+
+        // a exits, which releases the lock.
+        a.file = None;
+        assert!(b.confirm());
+        assert!(!a.confirm());
+
+        // The lock file is replaced, so b's lock no longer excludes anyone.
+        std::fs::remove_file(&path).unwrap();
+        assert!(a.confirm());
+        assert!(!b.confirm());
+
+        // a can't confirm the lock within the lease.
+        a.renewed = Some(Instant::now() - Duration::from_secs(61));
+        assert!(!a.is_leader());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,95 +2,81 @@ extern crate comrak;
 extern crate failure;
 extern crate git_version;
 extern crate htmlescape;
+extern crate hyper;
 extern crate lazy_static;
+extern crate libc;
 extern crate log;
 extern crate orca;
 extern crate regex;
+extern crate serde;
+extern crate serde_json;
 extern crate simple_logger;
+extern crate zeroize;
 
-use std::cell::RefMut;
-use std::collections::VecDeque;
+mod admin;
+mod config;
+mod credentials;
+mod detect;
+mod error;
+mod leader;
+mod poll;
+mod ratecap;
+mod review;
+mod revisit;
+mod state;
+
+use detect::{
+    contains_problematic_fenced_block, convert_fenced_blocks, normalize_body,
+    problematic_fenced_blocks,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::{Duration, Instant};
 
-/// If true, don't create or edit comments.
-const DRY_RUN: bool = false;
+/// The number of our own recent comments to load and check.
+const COMMENTS_MADE_LIMIT: usize = 100;
 
-const VERSION: &str = git_version::git_describe!("--always", "--dirty");
+/// How many times to retry a request that failed with a transient error.
+const MAX_RETRIES: u32 = 3;
 
-fn comrak_opts() -> comrak::ComrakOptions {
-    comrak::ComrakOptions {
-        ..comrak::ComrakOptions::default()
-    }
-}
+/// The most fullnames reddit accepts in one /api/info request.
+const INFO_BATCH_SIZE: usize = 100;
 
-/// Check if this reddit markdown body contains fenced code blocks that won't render well in old
-/// reddit.
-fn contains_problematic_fenced_block<'a>(body: &str) -> bool {
-    let arena = comrak::Arena::new();
-    let ast = comrak::parse_document(&arena, body, &comrak_opts());
-    lazy_static::lazy_static! {
-        static ref BAD_CONTENTS: regex::bytes::Regex = regex::bytes::Regex::new(r"\t|  |^ | \n").unwrap();
-    }
-    for node in ast.descendants() {
-        let mut n: RefMut<comrak::nodes::Ast> = node.data.borrow_mut();
-        match (*n).value {
-            comrak::nodes::NodeValue::CodeBlock(ref mut block) => {
-                let block: &mut comrak::nodes::NodeCodeBlock = block;
-                if block.fenced {
-                    // Some single line fenced blocks look OK. See
-                    // https://github.com/singron/old-reddit-fmt-bot/issues/1
-                    if !block.info.is_empty() {
-                        return true;
-                    }
-                    let b: &[u8] = &block.literal;
-                    if b.is_empty() {
-                        return true;
-                    }
-                    if b.get(b.len() - 1) != Some(&b'\n') {
-                        return true;
-                    }
-                    if b.iter().filter(|c| **c == b'\n').count() != 1 {
-                        return true;
-                    }
-                    if BAD_CONTENTS.is_match(b) {
-                        return true;
-                    }
+/// How many rechecks in a row the parent of a reply can be missing before it counts as deleted.
+const PARENT_MISSING_LIMIT: u32 = 6;
 
-                    if let Some(prev) = node.previous_sibling() {
-                        if !prev.data.borrow().last_line_blank {
-                            return true;
-                        }
-                    }
-                }
-            }
-            _ => (),
-        }
-    }
-    return false;
-}
+const ABOUT_URL: &str = "https://github.com/singron/old-reddit-fmt-bot/blob/master/about.md";
 
-/// Retrieve a password from pass (password-store).
-fn get_pass(p: &str) -> String {
-    let res = std::process::Command::new("pass")
-        .arg("show")
-        .arg("--")
-        .arg(p)
-        .output()
-        .unwrap();
-    if !res.status.success() {
-        panic!("pass failed");
-    }
-    let pass = String::from_utf8(res.stdout)
-        .unwrap()
-        .lines()
-        .next()
-        .unwrap()
-        .to_owned();
-    if pass.is_empty() {
-        panic!("pass is empty");
-    }
-    pass
-}
+/// How often to check the kill switch while no comments arrive. A file switch is also checked
+/// after every comment.
+const KILL_SWITCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often to retry comments queued because of a rate cap.
+const OVER_CAP_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to use a subreddit's moderator list before loading it again.
+const MODERATORS_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How often to fetch the comments due to be revisited.
+const REVISIT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most comments to hold while they are over a rate cap. The oldest are dropped first.
+const OVER_CAP_QUEUE_LIMIT: usize = 1000;
+
+/// How long to wait before checking whether a reply with an unknown outcome was created, in
+/// seconds.
+const INTENT_SETTLE_SECS: f64 = 60.0;
+
+/// How long to wait before sending a rate limited reply or edit again if reddit didn't say.
+const OUTBOX_DEFAULT_WAIT: Duration = Duration::from_secs(60);
+
+/// The reason given when reporting a comment. Reddit limits reasons to 100 characters.
+const REPORT_REASON: &str = "Fenced code block that doesn't render on old reddit";
+
+/// The longest code a summoned reply includes. Reddit comments are limited to 10000 characters.
+const SUMMONED_FIX_MAX_LEN: usize = 8000;
+
+const VERSION: &str = git_version::git_describe!("--always", "--dirty");
 
 /// Strip the type prefix (e.g. "t1_") from a reddit fullname.
 fn strip_type(s: &str) -> &str {
@@ -122,6 +108,59 @@ fn find_comment<'a>(
     None
 }
 
+/// The current time in seconds since the unix epoch, like reddit's created_utc.
+fn now_utc() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// How long ago something with this created_utc was created. This is zero if it seems to be from
+/// the future because of clock skew.
+fn comment_age(created_utc: f64) -> Duration {
+    Duration::from_secs_f64((now_utc() - created_utc).max(0.0))
+}
+
+/// How long until something that was last done at last should be done again. It's due now if it
+/// was never done.
+fn until_due(last: Option<Instant>, interval: Duration) -> Duration {
+    last.map(|i| interval.checked_sub(i.elapsed()).unwrap_or_default())
+        .unwrap_or_default()
+}
+
+/// Stop the bot because of an error that retrying can't fix.
+fn abort(e: &error::Error) -> ! {
+    log::error!("Stopping: {}", e);
+    std::process::exit(1);
+}
+
+/// When a comment was last edited, or None if it wasn't.
+fn edited_utc(comment: &orca::data::Comment) -> Option<f64> {
+    comment.edited.filter(|t| *t > 0.0)
+}
+
+/// Check if a reply looks like it already tells the author about the formatting problem.
+fn mentions_formatting(body: &str) -> bool {
+    let body = body.to_lowercase();
+    // Just talking about code blocks doesn't count.
+    ["old reddit", "four spaces", "4 spaces", "indent"]
+        .iter()
+        .any(|phrase| body.contains(phrase))
+}
+
+/// Check if a comment was deleted by its author or removed by a moderator. The author is also
+/// "[deleted]" when they deleted their account, but then the comment itself is still up.
+fn is_deleted_comment(comment: &orca::data::Comment) -> bool {
+    comment.body == "[deleted]" || comment.body == "[removed]"
+}
+
+/// Check if a username looks like a bot's, e.g. AutoModerator or some-code-bot.
+fn is_bot_name(username: &str) -> bool {
+    let lower = username.to_lowercase();
+    lower == "automoderator" || lower.ends_with("bot")
+}
+
 /// Backoff keeps track of net failures and conveniently allows waiting after failed requests.
 struct Backoff {
     fails: u64,
@@ -144,18 +183,121 @@ impl Backoff {
         ));
         self.fails = (self.fails + 1).min(10);
     }
+}
 
-    /// Automatically call ok or fail_wait and retry for a closure call. on_err will be called on
-    /// each error value.
-    fn loop_wait<T, E, C: Fn() -> Result<T, E>, H: Fn(E)>(&mut self, call: C, on_err: H) -> T {
-        loop {
-            match call() {
-                Ok(x) => {
-                    self.ok();
-                    return x;
-                }
-                Err(e) => on_err(e),
-            }
+/// RateLimiter spaces out requests shared by several threads so that together they stay under
+/// reddit's API rate limit.
+struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> RateLimiter {
+        RateLimiter {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait for our turn to make a request. Call this before each request.
+    fn wait(&self) {
+        let at = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let at = (*next).max(now);
+            *next = at + self.interval;
+            at
+        };
+        let now = Instant::now();
+        if at > now {
+            std::thread::sleep(at - now);
+        }
+    }
+}
+
+/// The state of a comment this bot made. Each state has its own reply text, so the state can be
+/// recovered from the text after a restart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReplyState {
+    /// The parent comment has problematic fenced blocks.
+    Flagged,
+    /// The author fixed the parent comment.
+    Fixed,
+    /// The author fixed the parent comment and then broke it again.
+    Reflagged,
+    /// The parent comment was deleted or removed. The reply is resolved and is no longer checked.
+    ParentDeleted,
+    /// A reply to someone who summoned the bot. It's never checked or edited, only remembered so
+    /// the mention isn't answered twice.
+    Summoned,
+}
+
+impl ReplyState {
+    /// The text that starts a reply in this state.
+    fn prefix(self) -> &'static str {
+        match self {
+            ReplyState::Flagged => "",
+            ReplyState::Fixed => "EDIT: Thanks for editing your comment!\n\n",
+            ReplyState::Reflagged => "EDIT: Your comment has fenced code blocks again.\n\n",
+            ReplyState::ParentDeleted => "EDIT: The parent comment was deleted or removed.",
+            // This is the start of write_diagnostics.
+            ReplyState::Summoned => "I checked [this comment]",
+        }
+    }
+
+    /// Recover the state from the body of a reply. Returns None if the comment isn't one of the
+    /// bot's replies, e.g. a comment posted by hand.
+    fn from_body(body: &str) -> Option<ReplyState> {
+        if body.starts_with(ReplyState::ParentDeleted.prefix()) {
+            return Some(ReplyState::ParentDeleted);
+        }
+        if body.starts_with(ReplyState::Summoned.prefix()) {
+            return Some(ReplyState::Summoned);
+        }
+        // This is the start of write_reply.
+        if !body.contains("Your comment uses fenced code blocks") {
+            return None;
+        }
+        if body.starts_with(ReplyState::Reflagged.prefix()) {
+            return Some(ReplyState::Reflagged);
+        }
+        // Older versions of this bot used other EDIT messages when the author fixed their comment.
+        // Only the start counts since the inline fix quotes the author's code.
+        if body.starts_with("EDIT:") {
+            Some(ReplyState::Fixed)
+        } else {
+            Some(ReplyState::Flagged)
+        }
+    }
+
+    /// The next state given the current parent comment, if there is a transition.
+    fn next(self, parent_deleted: bool, parent_problematic: bool) -> Option<ReplyState> {
+        let next = match self {
+            ReplyState::ParentDeleted | ReplyState::Summoned => return None,
+            _ if parent_deleted => ReplyState::ParentDeleted,
+            ReplyState::Flagged | ReplyState::Reflagged if !parent_problematic => ReplyState::Fixed,
+            ReplyState::Fixed if parent_problematic => ReplyState::Reflagged,
+            _ => return None,
+        };
+        Some(next)
+    }
+}
+
+/// Write the reply for a state. parent is only optional for ReplyState::ParentDeleted. The inline
+/// fix is included like in the first reply if inline_fix_max_len is set.
+fn write_reply_in_state(
+    out: &mut String,
+    state: ReplyState,
+    parent: Option<&orca::data::Comment>,
+    inline_fix_max_len: Option<usize>,
+) {
+    out.push_str(state.prefix());
+    if state != ReplyState::ParentDeleted {
+        let parent = parent.expect("parent comment");
+        write_reply(out, parent);
+        if let Some(max_len) = inline_fix_max_len {
+            write_inline_fix(out, &normalize_body(&parent.body), max_len);
         }
     }
 }
@@ -165,7 +307,8 @@ struct MadeComment {
     parent_name: String,
     name: String,
     link_id: String,
-    edited: bool,
+    created_utc: f64,
+    state: ReplyState,
 }
 
 fn write_reply(out: &mut String, comment: &orca::data::Comment) {
@@ -181,9 +324,116 @@ fn write_reply(out: &mut String, comment: &orca::data::Comment) {
          and \n\
          [old](https://old.reddit.com{permalink}) \n\
          reddit. \n\
-         [My page](https://github.com/singron/old-reddit-fmt-bot/blob/master/about.md) \n\
+         [My page]({about}) \n\
          has easy ways to indent code as well as information and source code for this bot.",
         permalink = EscapeMarkdownLink(&comment.permalink),
+        about = ABOUT_URL,
+    )
+    .unwrap();
+}
+
+/// Append the problematic fenced blocks of body, converted to indented code blocks, to a reply
+/// written by write_reply. Nothing is written if that would add more than max_len bytes, counting
+/// the indentation. Returns true if the blocks were written.
+fn write_inline_fix(out: &mut String, body: &str, max_len: usize) -> bool {
+    let blocks = problematic_fenced_blocks(body);
+    if blocks.is_empty() {
+        return false;
+    }
+    let mut fix =
+        String::from("\n\n---\n\nHere is your code indented with 4 spaces, ready to paste:");
+    for (idx, block) in blocks.iter().enumerate() {
+        // Consecutive indented blocks would merge into one, so label each of them.
+        if blocks.len() > 1 {
+            use std::fmt::Write;
+            write!(fix, "\n\nBlock {}:", idx + 1).unwrap();
+        }
+        fix.push('\n');
+        for line in block.literal.lines() {
+            fix.push_str("\n    ");
+            fix.push_str(line);
+        }
+    }
+    if fix.len() > max_len {
+        return false;
+    }
+    out.push_str(&fix);
+    true
+}
+
+/// Write the reply to someone who summoned the bot to check comment. Unlike write_reply, this is
+/// written even if there are no problems.
+fn write_diagnostics(out: &mut String, comment: &orca::data::Comment, body: &str) {
+    use std::fmt::Write;
+    let blocks = problematic_fenced_blocks(body);
+    if blocks.is_empty() {
+        write!(
+            out,
+            "I checked [this comment](https://www.reddit.com{permalink}) and didn't find \
+             anything that renders differently in old reddit.",
+            permalink = EscapeMarkdownLink(&comment.permalink),
+        )
+        .unwrap();
+    } else {
+        writeln!(
+            out,
+            "I checked [this comment](https://www.reddit.com{permalink}) and found {n} fenced \
+             code block{s} that won't render correctly in old reddit:",
+            permalink = EscapeMarkdownLink(&comment.permalink),
+            n = blocks.len(),
+            s = if blocks.len() == 1 { "" } else { "s" },
+        )
+        .unwrap();
+        for (idx, block) in blocks.iter().enumerate() {
+            write!(out, "\n* Block {} {}.", idx + 1, block.problem.describe()).unwrap();
+        }
+        if !write_inline_fix(out, body, SUMMONED_FIX_MAX_LEN) {
+            out.push_str("\n\nThe code is too long to include here.");
+        }
+    }
+    write!(
+        out,
+        "\n\n---\n\n[My page]({about}) has easy ways to indent code as well as information \
+         and source code for this bot.",
+        about = ABOUT_URL,
+    )
+    .unwrap();
+}
+
+/// Write the reply to a private message with markdown to convert.
+fn write_conversion(out: &mut String, body: &str) {
+    use std::fmt::Write;
+    let (converted, problems) = convert_fenced_blocks(body);
+    if problems.is_empty() {
+        out.push_str(
+            "I didn't find anything in your message that renders differently in old reddit.",
+        );
+    } else {
+        let mut indented = String::new();
+        for line in converted.lines() {
+            indented.push_str("\n    ");
+            indented.push_str(line);
+        }
+        if indented.len() > SUMMONED_FIX_MAX_LEN {
+            out.push_str("Your message is too long for me to include the converted version.");
+        } else {
+            out.push_str(
+                "Here is your markdown with the fenced code blocks converted to indented code \
+                 blocks, which render correctly in old and new reddit. Copy everything in the \
+                 code block below:\n",
+            );
+            out.push_str(&indented);
+        }
+        out.push_str("\n\nI fixed these problems:\n");
+        for (idx, problem) in problems.iter().enumerate() {
+            write!(out, "\n* Block {} {}.", idx + 1, problem.describe()).unwrap();
+        }
+    }
+    write!(
+        out,
+        "\n\n---\n\n[My page]({about}) has more ways to indent code as well as information \
+         and source code for this bot.",
+        about = ABOUT_URL,
     )
     .unwrap();
 }
@@ -191,52 +441,165 @@ fn write_reply(out: &mut String, comment: &orca::data::Comment) {
 struct Bot<'a> {
     app: &'a orca::App,
     username: &'a str,
-    subreddit_names: &'static [&'static str],
-    caches: Vec<VecDeque<orca::data::Comment>>,
-    recent_comment_names: Vec<VecDeque<String>>,
+    config: config::Config,
+    state: state::State,
+    /// Batches of comments from the poller.
+    new_comments: mpsc::Receiver<Vec<orca::data::Comment>>,
+    limiter: Arc<RateLimiter>,
     comments_made: Vec<MadeComment>,
     comments_made_dirty: bool,
     last_comments_made_check: Option<Instant>,
+    /// How many rechecks in a row each missing parent wasn't found, by name.
+    parent_misses: HashMap<String, u32>,
+    last_inbox_check: Option<Instant>,
     backoff: Backoff,
-    last_refresh: Option<Instant>,
     last_new_comment: Option<Instant>,
+    /// When the last new comment arrived from each subreddit, by lowercase name.
+    last_new_comment_by_subreddit: HashMap<String, Instant>,
+    /// The subreddits to watch, shared with the poller so operators can change them.
+    subreddits: Arc<Mutex<Vec<String>>>,
+    /// Set by operators. While paused, the bot only handles operator commands.
+    paused: bool,
+    started: Instant,
+    /// How long it has been since the last new comment, from 0 to 3. Higher modes log more.
+    error_mode: u32,
+    /// Whether the kill switch was set when last checked.
+    killed: bool,
+    last_kill_switch_check: Option<Instant>,
+    /// Comments that weren't replied to because of a rate cap, oldest first, if they are queued.
+    over_cap_queue: VecDeque<orca::data::Comment>,
+    last_over_cap_retry: Option<Instant>,
+    /// How many times a reply was held back by a rate cap.
+    cap_hits: u64,
+    /// Comments to fetch again in case they are edited.
+    revisits: revisit::Revisits,
+    last_revisit_check: Option<Instant>,
+    /// Replies waiting for an operator's approval in supervised mode.
+    review_queue: review::ReviewQueue,
+    /// Set if several instances share a leader lock.
+    leader: Option<leader::Leader>,
+    /// Moderators of each subreddit by lowercase name, and when they were loaded.
+    moderators: HashMap<String, (Instant, Vec<String>)>,
 }
 
 impl<'a> Bot<'a> {
     fn new(
         app: &'a orca::App,
         username: &'a str,
-        subreddit_names: &'static [&'static str],
+        config: config::Config,
+        state: state::State,
+        new_comments: mpsc::Receiver<Vec<orca::data::Comment>>,
+        subreddits: Arc<Mutex<Vec<String>>>,
+        limiter: Arc<RateLimiter>,
     ) -> Bot<'a> {
+        let revisits = revisit::Revisits::new(config.revisit_intervals_secs.clone());
+        let leader = config
+            .leader_lock
+            .clone()
+            .map(|path| leader::Leader::new(path, Duration::from_secs(config.leader_lease_secs)));
+        let review_queue = review::ReviewQueue::new(&config.review_dir);
         Bot {
             app,
             username,
-            subreddit_names,
-            caches: vec![VecDeque::new(); subreddit_names.len()],
-            recent_comment_names: vec![VecDeque::new(); subreddit_names.len()],
+            config,
+            state,
+            new_comments,
+            limiter,
             comments_made: Vec::new(),
             comments_made_dirty: true,
             last_comments_made_check: None,
-            last_refresh: None,
+            parent_misses: HashMap::new(),
+            last_inbox_check: None,
             last_new_comment: None,
+            last_new_comment_by_subreddit: HashMap::new(),
+            subreddits,
+            paused: false,
+            started: Instant::now(),
+            error_mode: 0,
+            killed: false,
+            last_kill_switch_check: None,
+            over_cap_queue: VecDeque::new(),
+            last_over_cap_retry: None,
+            cap_hits: 0,
+            revisits,
+            last_revisit_check: None,
+            review_queue,
+            leader,
+            moderators: HashMap::new(),
             backoff: Backoff { fails: 0 },
         }
     }
 
+    /// Make a request to reddit. Errors that require stopping the bot exit the process, and other
+    /// errors are returned for the caller to retry or skip.
+    fn call<T, F: Fn(&orca::App) -> Result<T, failure::Error>>(
+        &mut self,
+        what: &str,
+        f: F,
+    ) -> Result<T, error::Error> {
+        self.limiter.wait();
+        match f(self.app) {
+            Ok(x) => {
+                self.backoff.ok();
+                Ok(x)
+            }
+            Err(e) => {
+                let e = error::Error::from_reddit(e);
+                log::warn!("Error in {}: {}", what, e);
+                if e.action() == error::Action::Abort {
+                    abort(&e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Like call, but retry errors with Action::Retry a few times. Only use this for requests that
+    /// are safe to repeat.
+    fn call_retry<T, F: Fn(&orca::App) -> Result<T, failure::Error>>(
+        &mut self,
+        what: &str,
+        f: F,
+    ) -> Result<T, error::Error> {
+        let mut retries = 0;
+        loop {
+            match self.call(what, &f) {
+                Err(e) if e.action() == error::Action::Retry && retries < MAX_RETRIES => {
+                    retries += 1;
+                    match e {
+                        error::Error::RateLimit {
+                            wait: Some(wait), ..
+                        } => std::thread::sleep(wait),
+                        _ => self.backoff.fail_wait(),
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+
     /// Load or reload the list of comments this bot has made.
-    fn load_comments_made(&mut self) -> Result<(), failure::Error> {
+    fn load_comments_made(&mut self) -> Result<(), error::Error> {
         let mut opts = orca::app::UserListingOpts::default();
-        opts.limit(100);
-        let comments: orca::data::Listing<orca::data::Comment> =
-            self.app.get_user_comments(self.username, &opts)?;
+        opts.limit(COMMENTS_MADE_LIMIT as _);
+        let username = self.username;
+        let comments: orca::data::Listing<orca::data::Comment> = self
+            .call_retry("get_user_comments", |app| {
+                app.get_user_comments(username, &opts)
+            })?;
         let mut comments_made = Vec::with_capacity(comments.children.len());
         for comment in comments.children {
             let comment: orca::data::Comment = comment;
+            let state = match ReplyState::from_body(&normalize_body(&comment.body)) {
+                Some(x) => x,
+                None => continue,
+            };
             comments_made.push(MadeComment {
                 parent_name: comment.parent_id,
                 name: comment.name,
-                edited: comment.body.contains("EDIT:"),
+                state,
                 link_id: comment.link_id,
+                created_utc: comment.created_utc,
             });
         }
         self.comments_made = comments_made;
@@ -244,278 +607,1451 @@ impl<'a> Bot<'a> {
         Ok(())
     }
 
-    /// Load the caches of recent comments for each subreddit, and possibly refresh the list of
-    /// comments made.
-    fn refresh(&mut self) {
-        if let Some(last_refresh) = self.last_refresh {
-            let min_refresh = Duration::from_secs(5);
-            let e = last_refresh.elapsed();
-            if e < min_refresh {
-                std::thread::sleep(min_refresh - e);
-            }
+    /// Save the state if there is a state file.
+    /// Returns false if it couldn't be saved, including when there is no state file, since then
+    /// nothing survives a crash. Standbys never save, since the leader owns the state file.
+    fn save_state(&self) -> bool {
+        let path = match &self.config.state_path {
+            Some(x) => x,
+            None => return false,
+        };
+        if self.standby() {
+            return false;
         }
-        for (idx, subreddit) in self.subreddit_names.iter().enumerate() {
-            if !self.caches[idx].is_empty() {
-                continue;
-            }
-            let res: orca::data::Listing<orca::data::Comment> = loop {
-                let recent_comment = self.recent_comment_names[idx].front().map(|s| s.as_str());
-                match self
-                    .app
-                    .get_recent_comments(subreddit, Some(100), recent_comment)
-                {
-                    Ok(res) => {
-                        self.backoff.ok();
-                        if res.children.is_empty() && recent_comment.is_some() {
-                            // If we try to use a deleted comment as the `before` parameter when
-                            // getting recent comments, we will get empty results forever.
-                            let name = recent_comment.unwrap();
-                            let backoff = &mut self.backoff;
-                            let app = &mut self.app;
-                            let comment = backoff.loop_wait(
-                                || app.get_comment(name),
-                                |e| println!("Error in get_comment({:?}): {}", name, e),
-                            );
-                            if comment.is_none() || comment.unwrap().author == "[deleted]" {
-                                // We will use the next most recent comment, or eventually get
-                                // another listing from scratch.
-                                self.recent_comment_names[idx].pop_front();
-                                continue;
-                            }
-                        }
-                        break res;
-                    }
-                    Err(e) => {
-                        println!(
-                            "Error get_recent_comments({:?}, {:?}): {}",
-                            subreddit, recent_comment, e
-                        );
-                        self.backoff.fail_wait();
+        if let Err(e) = self.state.save(path) {
+            log::error!("Error saving state to {:?}: {}", path, e);
+            return false;
+        }
+        true
+    }
+
+    /// Load the state the old leader saved, e.g. its intents and outbox, after taking over from it.
+    /// The bot stops if it can't, since replying without the intents could reply twice.
+    ///
+    /// The comments held while standing by are kept. The poller is already past the old leader's
+    /// last save, so they are the only record of comments since then.
+    fn take_over_state(&mut self) {
+        let path = match &self.config.state_path {
+            Some(x) => x,
+            None => return,
+        };
+        match state::State::load(path) {
+            Ok(mut state) => {
+                for p in self.state.pending.drain(..) {
+                    if !state.pending.iter().any(|q| q.name == p.name) {
+                        state.pending.push(p);
                     }
                 }
-            };
-            let skip = if res.children.len() > 10 {
-                res.children.len() - 10
-            } else {
-                0
-            };
-            for c in res.children.iter().rev().skip(skip) {
-                self.recent_comment_names[idx].push_front(c.name.clone());
+                self.state = state;
             }
-            if self.recent_comment_names[idx].len() > 10 {
-                self.recent_comment_names[idx].truncate(10);
+            Err(e) => {
+                log::error!("Error loading the old leader's state {:?}: {}", path, e);
+                std::process::exit(1);
             }
-            // get_recent_comments returns reverse-chronological order, so unreverse it.
-            self.caches[idx].extend(res.children.into_iter().rev());
         }
+    }
+
+    /// Reload the list of comments made if we made new comments since it was loaded. If that
+    /// fails, it stays dirty and is reloaded next time.
+    fn refresh_comments_made(&mut self) {
         if self.comments_made_dirty {
-            loop {
-                match self.load_comments_made() {
-                    Ok(x) => {
-                        self.backoff.ok();
-                        break x;
-                    }
-                    Err(e) => {
-                        println!("Error load_comments_made: {}", e);
-                        self.backoff.fail_wait();
-                    }
-                }
+            // Errors were already logged.
+            let _ = self.load_comments_made();
+        }
+    }
+
+    /// Fetch comments by fullname through /api/info, in batches as large as reddit allows.
+    /// Comments that don't exist are left out of the result. Transient errors are retried, and if
+    /// a batch still fails the whole fetch fails, so callers keep what they were checking and try
+    /// again next time.
+    fn fetch_info(
+        &mut self,
+        names: &[String],
+    ) -> Result<HashMap<String, orca::data::Comment>, error::Error> {
+        let mut comments = HashMap::with_capacity(names.len());
+        for batch in names.chunks(INFO_BATCH_SIZE) {
+            let listing = self.call_retry("get_info", |app| {
+                let names: Vec<&str> = batch.iter().map(|s| s.as_str()).collect();
+                app.get_info(&names)
+            })?;
+            comments.extend(listing.children.into_iter().map(|c| (c.name.clone(), c)));
+        }
+        Ok(comments)
+    }
+
+    /// Check the existing replies to a comment. Returns the reason to skip it if this bot already
+    /// replied, someone else already pointed out the problem, or the replies couldn't be loaded.
+    fn check_existing_replies(&mut self, comment: &orca::data::Comment) -> Result<(), String> {
+        if self
+            .comments_made
+            .iter()
+            .any(|c| c.parent_name == comment.name)
+        {
+            return Err("already replied".to_string());
+        }
+        // This comment from the comments stream doesn't include replies, so load its permalink
+        // down to its direct replies at depth 2. That costs the same however big the thread is.
+        // comments_made only has our most recent comments, so this also catches older replies of
+        // ours.
+        let link_id = strip_type(&comment.link_id);
+        let tree = self
+            .call_retry(&format!("get_comment_subtree({:?})", comment.name), |app| {
+                app.get_comment_subtree(link_id, &comment.id, 2)
+            })
+            .map_err(|e| format!("could not load the replies: {}", e))?;
+        // Maybe it was deleted?
+        let tree_comment = find_comment(&tree, &comment.id).ok_or_else(|| {
+            format!(
+                "could not find comment {} on link {}",
+                comment.id, comment.link_id
+            )
+        })?;
+        for reply in &tree_comment.replies.children {
+            if reply.author == self.username {
+                return Err("already replied".to_string());
+            }
+            if self
+                .config
+                .formatting_bots
+                .iter()
+                .any(|b| b.eq_ignore_ascii_case(&reply.author))
+            {
+                return Err(format!("u/{} already replied", reply.author));
+            }
+            if mentions_formatting(&normalize_body(&reply.body)) {
+                return Err(format!(
+                    "u/{} already pointed out the problem in {}",
+                    reply.author, reply.name
+                ));
             }
         }
-        self.last_refresh = Some(Instant::now());
+        Ok(())
     }
 
-    /// This is called for each recent comment in the requested subreddits.
-    fn on_new_comment(&mut self, comment: orca::data::Comment) {
-        use std::convert::TryFrom;
-        let created = std::time::SystemTime::UNIX_EPOCH
-            .checked_add(std::time::Duration::from_secs(
-                u64::try_from(comment.created_utc as i64).unwrap(),
-            ))
-            .unwrap();
-        let age = created.elapsed().unwrap();
-        println!("https://www.reddit.com{} {:?}", comment.permalink, age);
-        // Never reply to ourselves
-        if comment.author == self.username {
-            return;
+    /// Check if a user asked the bot to leave them alone.
+    fn is_opted_out(&self, username: &str) -> bool {
+        self.config
+            .opted_out_users
+            .iter()
+            .any(|u| u.eq_ignore_ascii_case(username))
+    }
+
+    /// Check if a comment's author should be left alone: ignored users, bots, and moderators in
+    /// their own subreddit.
+    fn is_ignored(&mut self, comment: &orca::data::Comment) -> bool {
+        let author = &comment.author;
+        if self.is_ignored_user(author) {
+            return true;
         }
-        // Reddit responses are entity encoded for legacy reasons unless the client passes
-        // raw_json=1, which orca doesn't.
-        let body = match htmlescape::decode_html(&comment.body) {
-            Err(e) => {
-                println!("Error: {:?}", e);
-                return;
+        // Set for moderators and admins speaking officially.
+        if self.config.ignore_distinguished && comment.distinguished.is_some() {
+            return true;
+        }
+        self.config.exempt_moderators && self.is_moderator(&comment.subreddit, author)
+    }
+
+    /// Check if a user is ignored by name: configured ignored users and, if enabled, bots.
+    fn is_ignored_user(&self, username: &str) -> bool {
+        self.config
+            .ignored_users
+            .iter()
+            .any(|u| u.eq_ignore_ascii_case(username))
+            || (self.config.ignore_bot_names && is_bot_name(username))
+    }
+
+    /// Check if a user moderates a subreddit, using a cached moderator list. If the list can't be
+    /// loaded, the expired list is used, or nobody is treated as a moderator if there is none.
+    fn is_moderator(&mut self, subreddit: &str, username: &str) -> bool {
+        let key = subreddit.to_lowercase();
+        let fresh = match self.moderators.get(&key) {
+            Some((loaded, _)) => loaded.elapsed() < MODERATORS_TTL,
+            None => false,
+        };
+        if !fresh {
+            // A failure isn't cached, so the next comment tries again.
+            if let Ok(moderators) = self
+                .call_retry(&format!("get_moderators({:?})", subreddit), |app| {
+                    app.get_moderators(subreddit)
+                })
+            {
+                self.moderators
+                    .insert(key.clone(), (Instant::now(), moderators));
             }
+        }
+        self.moderators.get(&key).map_or(false, |(_, moderators)| {
+            moderators.iter().any(|m| m.eq_ignore_ascii_case(username))
+        })
+    }
+
+    /// Check if a subreddit banned the bot or asked it not to participate. See banned.md.
+    fn is_banned_subreddit(&self, subreddit: &str) -> bool {
+        self.config
+            .banned_subreddits
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(subreddit))
+    }
+
+    /// Handle unread messages in the inbox.
+    fn check_inbox(&mut self) {
+        self.last_inbox_check = Some(Instant::now());
+        let messages = match self.call_retry("get_unread_messages", |app| app.get_unread_messages())
+        {
             Ok(x) => x,
+            Err(_) => return,
         };
-        if !contains_problematic_fenced_block(&body) {
-            return;
+        for message in messages.children {
+            // Operators can still resume the bot while it's paused.
+            let from_operator = !message.was_comment && self.is_operator(&message.author);
+            if self.paused && !from_operator {
+                // Leave it unread to handle after resuming.
+                continue;
+            }
+            // Mark each message read before handling it, even in a dry run, so a crash or a
+            // failed mark_read can't lead to a second reply. If it can't be marked read, it's left
+            // for the next check.
+            if self
+                .call_retry(&format!("mark_read({:?})", message.name), |app| {
+                    app.mark_read(&message.name)
+                })
+                .is_err()
+            {
+                continue;
+            }
+            if from_operator {
+                self.on_operator_message(&message);
+            } else {
+                self.on_message(&message);
+            }
+        }
+    }
+
+    /// This is called for each unread inbox message that isn't from an operator.
+    fn on_message(&mut self, message: &orca::data::Message) {
+        if message.was_comment {
+            // Replies to our comments show up here too, but don't need anything from us.
+            if message.subject == "username mention" {
+                self.on_mention(message);
+            }
+        } else {
+            self.on_private_message(message);
+        }
+    }
+
+    /// Check if the bot should only log what it would post, because of the config, an operator
+    /// or the kill switch.
+    fn dry_run(&self) -> bool {
+        self.config.dry_run || self.killed || self.standby()
+    }
+
+    /// Check if another instance is the leader. A standby keeps polling so it's ready to take
+    /// over, but doesn't post or handle the inbox.
+    fn standby(&self) -> bool {
+        self.leader.as_ref().map_or(false, |l| !l.is_leader())
+    }
+
+    /// Check that this instance still leads right before posting, since another instance may have
+    /// taken over since the last check. Always true without a leader_lock.
+    fn confirm_leader(&mut self) -> bool {
+        let confirmed = self.leader.as_mut().map_or(true, |l| l.confirm());
+        if !confirmed {
+            println!("No longer the leader: not posting");
         }
-        // This comment from the comments stream doesn't include replies, so let's load the
-        // whole tree.
-        let tree = loop {
-            match self.app.get_comment_tree(strip_type(&comment.link_id)) {
-                Err(e) => {
-                    println!("Error: {:?}", e);
+        confirmed
+    }
+
+    /// Check the kill switch and update killed. If it can't be checked, killed is left as is.
+    fn check_kill_switch(&mut self) {
+        let killed = match &self.config.kill_switch {
+            None => return,
+            Some(config::KillSwitch::File { path }) => {
+                self.last_kill_switch_check = Some(Instant::now());
+                std::path::Path::new(path).exists()
+            }
+            Some(config::KillSwitch::Wiki { page }) => {
+                if until_due(self.last_kill_switch_check, KILL_SWITCH_INTERVAL)
+                    != Duration::from_secs(0)
+                {
                     return;
                 }
-                Ok(x) => break x,
-            }
-        };
-        let tree_comment = find_comment(&tree, &comment.id);
-        if let Some(tree_comment) = tree_comment {
-            let mut already_replied = false;
-            for reply in &tree_comment.replies.children {
-                if reply.author == self.username {
-                    already_replied = true;
-                    break;
+                self.last_kill_switch_check = Some(Instant::now());
+                let subreddit = format!("u_{}", self.username);
+                let page = page.clone();
+                match self.call_retry(&format!("get_wiki_page({:?})", page), |app| {
+                    app.get_wiki_page(&subreddit, &page)
+                }) {
+                    Ok(x) => !x.content_md.trim().is_empty(),
+                    // A missing page is an unset switch.
+                    Err(error::Error::NotFound(_)) => false,
+                    Err(_) => return,
                 }
             }
-            // Don't reply to the same comment again.
-            if already_replied {
-                return;
+        };
+        if killed != self.killed {
+            if killed {
+                log::error!("Kill switch set. Not posting or editing until it's cleared.");
+            } else {
+                log::error!("Kill switch cleared");
             }
-        } else {
-            // Maybe it was deleted?
-            println!(
-                "Could not find comment {} on link {}",
-                comment.id, comment.link_id
-            );
-            return;
-        }
-        let max_age = std::time::Duration::from_secs(60 * 60 * 24); // 24h
-        if age > max_age {
-            return;
+            self.killed = killed;
         }
-        println!("{}", body);
-        let mut reply = String::new();
-        write_reply(&mut reply, &comment);
+    }
+
+    /// Check if a user can control the bot.
+    fn is_operator(&self, username: &str) -> bool {
+        self.config
+            .operators
+            .iter()
+            .any(|u| u.eq_ignore_ascii_case(username))
+    }
+
+    /// An operator sent the bot a private message. Run the command in it and reply with the
+    /// result. The reply is sent even in a dry run, since that's the only way to see the result.
+    fn on_operator_message(&mut self, message: &orca::data::Message) {
+        println!("Command from u/{}: {:?}", message.author, message.body);
+        let body = normalize_body(&message.body);
+        let reply = match admin::Command::parse(&body) {
+            Some(command) => self.run_command(command),
+            None => admin::HELP.to_string(),
+        };
         println!("{}", &reply);
-        if DRY_RUN {
-            println!("DRY_RUN: not commenting");
+        if self.killed {
+            println!("Kill switch set: not replying");
             return;
         }
-        self.comments_made_dirty = true;
-        if let Err(e) = self.app.comment(&reply, &comment.name) {
-            println!("Error in comment: {}", e);
+        if !self.confirm_leader() {
+            return;
         }
+        // Don't retry. The reply may have been created even if the request failed.
+        let _ = self.call(&format!("comment({:?})", message.name), |app| {
+            app.comment(&reply, &message.name)
+        });
     }
 
-    /// Check each comment this bot has made. For now, this bot checks if the parent comment has
-    /// been remediated and then edits its own comment.
-    fn check_comments_made(&mut self) {
-        for comment_made in &mut self.comments_made {
-            if comment_made.edited {
-                continue;
+    /// Run an operator command and return the reply.
+    fn run_command(&mut self, command: admin::Command) -> String {
+        use admin::Command;
+        match command {
+            Command::Pause => {
+                self.paused = true;
+                "Paused.".to_string()
             }
-            let tree = match self.app.get_comment_tree(strip_type(&comment_made.link_id)) {
-                Err(e) => {
-                    println!(
-                        "Error in get_comment_tree({:?}): {}",
-                        comment_made.link_id, e
+            Command::Resume => {
+                self.paused = false;
+                "Resumed.".to_string()
+            }
+            Command::Status => self.status(),
+            Command::AddSubreddit(name) => {
+                if self.is_banned_subreddit(&name) {
+                    return format!(
+                        "Not watching r/{} because it banned the bot. See banned.md.",
+                        name
                     );
-                    self.backoff.fail_wait();
-                    continue;
                 }
-                Ok(x) => {
-                    self.backoff.ok();
-                    x
+                let mut subreddits = self.subreddits.lock().unwrap();
+                if subreddits.iter().any(|s| s.eq_ignore_ascii_case(&name)) {
+                    format!("Already watching r/{}.", name)
+                } else {
+                    subreddits.push(name.clone());
+                    format!("Watching r/{}.", name)
                 }
-            };
-            let parent_comment = match find_comment(&tree, strip_type(&comment_made.parent_name)) {
-                Some(x) => x,
-                None => {
-                    println!(
-                        "Could not find comment {} in {}",
-                        comment_made.parent_name, comment_made.link_id
-                    );
-                    continue;
+            }
+            Command::RemoveSubreddit(name) => {
+                let mut subreddits = self.subreddits.lock().unwrap();
+                let len = subreddits.len();
+                subreddits.retain(|s| !s.eq_ignore_ascii_case(&name));
+                if subreddits.len() == len {
+                    format!("Not watching r/{}.", name)
+                } else {
+                    format!("Stopped watching r/{}.", name)
                 }
-            };
-            if contains_problematic_fenced_block(&parent_comment.body) {
-                continue;
             }
-            // They fixed their comment
-            println!(
-                "Should edit reply to https://www.reddit.com{}",
-                parent_comment.permalink
+            Command::Review => match self.review_queue.list() {
+                Ok(names) if names.is_empty() => "Nothing to review.".to_string(),
+                Ok(names) => {
+                    let mut out = "Waiting for review:\n".to_string();
+                    for name in names {
+                        out.push_str(&format!("\n* {}", name));
+                    }
+                    out
+                }
+                Err(e) => format!("Error listing the review queue: {}", e),
+            },
+            Command::Approve(name) => self.approve(&name),
+            Command::Reject {
+                name,
+                false_positive,
+                reason,
+            } => match self.review_queue.take(&name) {
+                Ok(Some(item)) => match review::save_negative(
+                    &self.config.corpus_dir,
+                    &item,
+                    false_positive,
+                    &reason,
+                ) {
+                    Ok(()) => format!("Rejected {} and saved it to the corpus.", name),
+                    Err(e) => format!(
+                        "Rejected {}, but couldn't save it to the corpus: {}",
+                        name, e
+                    ),
+                },
+                Ok(None) => format!("{} isn't waiting for review.", name),
+                Err(e) => format!("Error reading {}: {}", name, e),
+            },
+            Command::DryRun(on) => {
+                self.config.dry_run = on;
+                format!("Dry run {}.", if on { "on" } else { "off" })
+            }
+        }
+    }
+
+    /// Deliver a reply waiting for review if the comment still needs it. The comment is fetched
+    /// again since it may have changed while it waited.
+    fn approve(&mut self, name: &str) -> String {
+        if !self.review_queue.contains(name) {
+            return format!("{} isn't waiting for review.", name);
+        }
+        // deliver wouldn't post anything, so leave it queued.
+        if self.dry_run() {
+            return format!(
+                "Not delivering during a dry run, kill switch or standby, so {} is still waiting.",
+                name
             );
-            let mut new_reply = "EDIT: Thanks for editing your comment!\n\n".to_string();
-            write_reply(&mut new_reply, &parent_comment);
-            println!("{}", new_reply);
-            if DRY_RUN {
-                println!("DRY_RUN: not editing")
-            } else {
-                if let Err(e) = self.app.edit(&new_reply, &comment_made.name) {
-                    println!("Error in edit({:?}): {}", &comment_made.name, e);
+        }
+        let comment = match self.fetch_info(&[name.to_string()]) {
+            Ok(mut x) => x.remove(name),
+            Err(e) => return format!("Error fetching {}, so it's still waiting: {}", name, e),
+        };
+        let res = match comment {
+            None => Err("it's gone".to_string()),
+            Some(ref c) if is_deleted_comment(c) => Err("it was deleted or removed".to_string()),
+            Some(c) => {
+                let body = normalize_body(&c.body);
+                if !contains_problematic_fenced_block(&body) {
+                    Err("it was fixed".to_string())
+                } else {
+                    self.check_not_handled(&c)
+                        .map(|()| self.review_item(&c, body))
                 }
             }
-            comment_made.edited = true;
+        };
+        if let Ok(item) = &res {
+            if let Some(cap) = self.state.reply_log.exceeded(
+                &self.config.rate_caps,
+                &item.subreddit,
+                &item.link_id,
+            ) {
+                return format!(
+                    "Reached the {} rate cap, so {} is still waiting.",
+                    cap, name
+                );
+            }
+        }
+        if let Err(e) = self.review_queue.take(name) {
+            return format!("Error reading {}: {}", name, e);
+        }
+        match res {
+            Ok(item) => {
+                self.deliver(&item);
+                format!("Approved {}.", name)
+            }
+            Err(reason) => format!("Discarded {}: {}.", name, reason),
         }
-        self.last_comments_made_check = Some(Instant::now());
     }
 
-    // Run the main processing loop.
+    /// Describe how the bot is doing for the status command.
+    fn status(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        let uptime = self.started.elapsed().as_secs();
+        write!(
+            out,
+            "* Version: {}\n\
+             * Uptime: {}d {}h {}m\n\
+             * Paused: {}\n\
+             * Dry run: {}\n\
+             * Kill switch: {}\n\
+             * Standby: {}\n\
+             * Consecutive failures: {}\n\
+             * Error mode: {}\n\
+             * Rate cap hits: {}\n\
+             * Queued over rate caps: {}\n\
+             * Held for the grace period: {}\n\
+             * Watching for edits: {}\n\
+             * Outbox: {}\n",
+            VERSION,
+            uptime / (60 * 60 * 24),
+            uptime / (60 * 60) % 24,
+            uptime / 60 % 60,
+            self.paused,
+            self.config.dry_run,
+            self.killed,
+            self.standby(),
+            self.backoff.fails,
+            self.error_mode,
+            self.cap_hits,
+            self.over_cap_queue.len(),
+            self.state.pending.len(),
+            self.revisits.len(),
+            self.state.outbox.len(),
+        )
+        .unwrap();
+        if !self.state.pending.is_empty() {
+            writeln!(out, "\nHeld for the grace period:\n").unwrap();
+            let now_utc = now_utc();
+            for p in &self.state.pending {
+                writeln!(
+                    out,
+                    "* {}: due in {}s",
+                    p.name,
+                    (p.due_utc - now_utc).max(0.0) as u64
+                )
+                .unwrap();
+            }
+        }
+        writeln!(out, "\nLast new comment:\n").unwrap();
+        for subreddit in self.subreddits.lock().unwrap().iter() {
+            match self
+                .last_new_comment_by_subreddit
+                .get(&subreddit.to_lowercase())
+            {
+                Some(t) => writeln!(out, "* r/{}: {}s ago", subreddit, t.elapsed().as_secs()),
+                None => writeln!(out, "* r/{}: never", subreddit),
+            }
+            .unwrap();
+        }
+        out
+    }
+
+    /// Someone sent the bot a private message. Treat the body as markdown to convert.
+    fn on_private_message(&mut self, message: &orca::data::Message) {
+        println!("Message from u/{}: {:?}", message.author, message.subject);
+        // Messages from reddit itself, e.g. ban notices, have no author to reply to.
+        if message.author.is_empty() || message.author == "[deleted]" {
+            return;
+        }
+        // Replies in a thread answer something the bot sent, e.g. a delivery or an earlier
+        // conversion. Answering those, or other bots, could loop forever.
+        if message.parent_id.is_some() || is_bot_name(&message.author) {
+            println!("Not replying to message {}", message.name);
+            return;
+        }
+        if self.is_opted_out(&message.author) || self.is_ignored_user(&message.author) {
+            return;
+        }
+        let body = normalize_body(&message.body);
+        let mut reply = String::new();
+        write_conversion(&mut reply, &body);
+        println!("{}", &reply);
+        if self.dry_run() {
+            println!("Dry run: not replying");
+            return;
+        }
+        if !self.confirm_leader() {
+            return;
+        }
+        // Don't retry. The reply may have been created even if the request failed.
+        let _ = self.call(&format!("comment({:?})", message.name), |app| {
+            app.comment(&reply, &message.name)
+        });
+    }
+
+    /// Someone mentioned the bot in a comment, so check the comment they replied to.
+    fn on_mention(&mut self, mention: &orca::data::Message) {
+        println!(
+            "Mentioned by u/{}: https://www.reddit.com{}",
+            mention.author, mention.context
+        );
+        if let Some(subreddit) = &mention.subreddit {
+            if self.is_banned_subreddit(subreddit) {
+                return;
+            }
+        }
+        if mention.author == self.username || self.is_opted_out(&mention.author) {
+            return;
+        }
+        // The mention stays unread if the bot stops before marking it read.
+        if self
+            .comments_made
+            .iter()
+            .any(|c| c.parent_name == mention.name)
+            || self
+                .state
+                .intents
+                .iter()
+                .any(|i| i.parent_name == mention.name)
+        {
+            println!("Already replied to mention {}", mention.name);
+            return;
+        }
+        let parent_name = match &mention.parent_id {
+            Some(x) if x.starts_with("t1_") => x.clone(),
+            _ => {
+                println!("Mention {} isn't a reply to a comment", mention.name);
+                return;
+            }
+        };
+        let parent = match self.fetch_info(&[parent_name.clone()]) {
+            Ok(mut x) => x.remove(&parent_name),
+            Err(_) => return,
+        };
+        let parent = match parent {
+            Some(x) => x,
+            None => return,
+        };
+        if is_deleted_comment(&parent) || self.is_opted_out(&parent.author) {
+            return;
+        }
+        let body = normalize_body(&parent.body);
+        let mut reply = String::new();
+        write_diagnostics(&mut reply, &parent, &body);
+        println!("{}", &reply);
+        if self.dry_run() {
+            println!("Dry run: not commenting");
+            return;
+        }
+        let _ = self.post_reply(&mention.name, None, &reply);
+    }
+
+    /// This is called for each recent comment in the requested subreddits. If hold is true and
+    /// there is a grace period, a flagged comment is queued to be checked again later instead of
+    /// replied to. A standby holds flagged comments even without a grace period, in case the leader
+    /// never gets to them, and so does a paused bot, so they are checked after resuming.
+    fn on_new_comment(&mut self, comment: orca::data::Comment, hold: bool) {
+        let age = comment_age(comment.created_utc);
+        println!("https://www.reddit.com{} {:?}", comment.permalink, age);
+        // The listing covers all of our subreddits, so find this comment's own subreddit.
+        if !self
+            .subreddits
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(&comment.subreddit))
+        {
+            println!("Ignoring comment from r/{}", comment.subreddit);
+            return;
+        }
+        // Never reply to ourselves
+        if comment.author == self.username {
+            return;
+        }
+        if self.is_opted_out(&comment.author) {
+            return;
+        }
+        let body = normalize_body(&comment.body);
+        if !contains_problematic_fenced_block(&body) {
+            return;
+        }
+        if age > Duration::from_secs(self.config.max_age_secs) {
+            return;
+        }
+        // This may need to load the moderator list, so only check comments we'd reply to.
+        if self.is_ignored(&comment) {
+            println!("Ignoring comment by u/{}", comment.author);
+            return;
+        }
+        if hold && (self.config.grace_period_secs > 0 || self.standby() || self.paused) {
+            if self.state.pending.iter().any(|p| p.name == comment.name) {
+                println!("Already holding {}", comment.name);
+                return;
+            }
+            println!("Holding {} for the grace period", comment.name);
+            // An edit found by a revisit gets the grace period from when it was edited.
+            let changed_utc = edited_utc(&comment).unwrap_or(comment.created_utc);
+            self.state.pending.push(state::Pending {
+                name: comment.name.clone(),
+                due_utc: changed_utc + self.config.grace_period_secs as f64,
+            });
+            return;
+        }
+        if self.review_queue.contains(&comment.name) {
+            println!("Skipping {}: waiting for review", comment.name);
+            return;
+        }
+        if let Err(reason) = self.check_not_handled(&comment) {
+            println!("Skipping {}: {}", comment.name, reason);
+            return;
+        }
+        if let Some(cap) = self.state.reply_log.exceeded(
+            &self.config.rate_caps,
+            &comment.subreddit,
+            &comment.link_id,
+        ) {
+            self.cap_hits += 1;
+            log::warn!(
+                "Reached the {} rate cap, {} reply to {}",
+                cap,
+                match self.config.over_cap_action {
+                    config::OverCapAction::Skip => "skipping",
+                    config::OverCapAction::Queue => "queueing",
+                },
+                comment.name
+            );
+            if self.config.over_cap_action == config::OverCapAction::Queue {
+                if self.over_cap_queue.len() >= OVER_CAP_QUEUE_LIMIT {
+                    self.over_cap_queue.pop_front();
+                }
+                self.over_cap_queue.push_back(comment);
+            }
+            return;
+        }
+        println!("{}", body);
+        let item = self.review_item(&comment, body);
+        println!("{}", &item.reply);
+        if self.config.supervised {
+            // Only the leader reads the inbox, where operators approve items.
+            if self.standby() {
+                println!("Standby: not queueing {} for review", item.name);
+                return;
+            }
+            match self.review_queue.add(&item) {
+                Ok(()) => println!("Supervised: queued {} for review", item.name),
+                Err(e) => log::error!("Error queueing {} for review: {}", item.name, e),
+            }
+            return;
+        }
+        self.deliver(&item);
+    }
+
+    /// Return why the bot shouldn't tell the author about a comment again, if it already did or
+    /// may have.
+    fn check_not_handled(&mut self, comment: &orca::data::Comment) -> Result<(), String> {
+        if self
+            .state
+            .private_deliveries
+            .iter()
+            .any(|d| d.parent_name == comment.name)
+        {
+            return Err("already told privately".to_string());
+        }
+        if self
+            .state
+            .intents
+            .iter()
+            .any(|i| i.parent_name == comment.name)
+        {
+            return Err("a reply may already exist".to_string());
+        }
+        if self.state.outbox.iter().any(|item| match &item.kind {
+            state::OutboxKind::Reply { parent_name, .. } => *parent_name == comment.name,
+            state::OutboxKind::Edit { .. } => false,
+        }) {
+            return Err("a reply is queued".to_string());
+        }
+        self.check_existing_replies(comment)
+    }
+
+    /// Write the reply to a problematic comment with the normalized body.
+    fn review_item(&self, comment: &orca::data::Comment, body: String) -> review::ReviewItem {
+        let mut reply = String::new();
+        write_reply(&mut reply, comment);
+        if let Some(max_len) = self.config.inline_fix_max_len {
+            write_inline_fix(&mut reply, &body, max_len);
+        }
+        review::ReviewItem {
+            name: comment.name.clone(),
+            author: comment.author.clone(),
+            subreddit: comment.subreddit.clone(),
+            link_id: comment.link_id.clone(),
+            permalink: comment.permalink.clone(),
+            problems: problematic_fenced_blocks(&body)
+                .iter()
+                .map(|b| b.problem.describe().to_string())
+                .collect(),
+            body,
+            reply,
+        }
+    }
+
+    /// Tell the author of a comment about its problems in the way configured for its subreddit.
+    fn deliver(&mut self, item: &review::ReviewItem) {
+        let reply = &item.reply;
+        let delivery = self.config.delivery_for(&item.subreddit);
+        if delivery == config::Delivery::LogOnly {
+            println!("Log only: not delivering");
+            return;
+        }
+        if self.dry_run() {
+            println!("Dry run: not delivering ({:?})", delivery);
+            return;
+        }
+        // Count it even if it fails, since it may have been created anyway.
+        self.state.reply_log.record(&item.subreddit, &item.link_id);
+        if delivery == config::Delivery::Reply {
+            let _ = self.post_reply(&item.name, Some(&item.link_id), reply);
+            return;
+        }
+        if !self.confirm_leader() {
+            return;
+        }
+        // Bot comments are found again through the user listing, but nothing lists messages or
+        // reports, so remember them. They are saved first so a crash can't send one twice.
+        self.state.private_deliveries.push(state::PrivateDelivery {
+            parent_name: item.name.clone(),
+            author: item.author.clone(),
+            permalink: item.permalink.clone(),
+            delivery,
+            created_utc: now_utc(),
+        });
+        if !self.save_state() {
+            self.state.private_deliveries.pop();
+            log::error!(
+                "Not delivering to {} because the delivery couldn't be saved",
+                item.name
+            );
+            return;
+        }
+        // Don't retry. They may have gone through even if the request failed.
+        let res = match delivery {
+            config::Delivery::Pm => self
+                .call(&format!("message({:?})", item.author), |app| {
+                    app.message(
+                        &item.author,
+                        &format!("Your comment in r/{}", item.subreddit),
+                        reply,
+                    )
+                })
+                .map(|_| ()),
+            config::Delivery::Report => self
+                .call(&format!("report({:?})", item.name), |app| {
+                    app.report(&item.name, REPORT_REASON)
+                })
+                .map(|_| ()),
+            config::Delivery::Reply | config::Delivery::LogOnly => return,
+        };
+        if let Err(e) = res {
+            log::warn!(
+                "{:?} for {} may not have been delivered: {}",
+                delivery,
+                item.name,
+                e
+            );
+        }
+    }
+
+    /// Reply to a comment at most once, even if the bot crashes or the request fails after reddit
+    /// created the reply. The intent to reply is saved first, and if the outcome is unknown it is
+    /// resolved later by reconcile_intents instead of posting again.
+    fn post_reply(
+        &mut self,
+        parent_name: &str,
+        link_id: Option<&str>,
+        reply: &str,
+    ) -> Result<(), error::Error> {
+        if !self.confirm_leader() {
+            return Ok(());
+        }
+        self.state.intents.push(state::Intent {
+            parent_name: parent_name.to_string(),
+            link_id: link_id.map(|s| s.to_string()),
+            created_utc: now_utc(),
+        });
+        if !self.save_state() {
+            // Without a saved intent, a crash could lead to a second reply.
+            self.state.intents.pop();
+            log::error!(
+                "Not replying to {} because the intent couldn't be saved",
+                parent_name
+            );
+            return Ok(());
+        }
+        self.comments_made_dirty = true;
+        // Don't retry. The comment may have been created even if the request failed.
+        let res = self
+            .call(&format!("comment({:?})", parent_name), |app| {
+                app.comment(reply, parent_name)
+            })
+            .map(|_| ());
+        match res {
+            Ok(()) => {
+                self.state.intents.retain(|i| i.parent_name != parent_name);
+                self.save_state();
+                Ok(())
+            }
+            // A rate limited reply wasn't created, so it's safe to send it again later.
+            Err(error::Error::RateLimit { wait, .. }) => {
+                self.state.intents.retain(|i| i.parent_name != parent_name);
+                self.queue_outbox(
+                    state::OutboxKind::Reply {
+                        parent_name: parent_name.to_string(),
+                        link_id: link_id.map(|s| s.to_string()),
+                    },
+                    reply,
+                    wait,
+                );
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Edit a comment. If reddit rate limits the edit, it's queued in the outbox.
+    fn edit(&mut self, name: &str, text: &str) -> Result<(), error::Error> {
+        match self.call(&format!("edit({:?})", name), |app| app.edit(text, name)) {
+            Ok(_) => Ok(()),
+            Err(error::Error::RateLimit { wait, .. }) => {
+                self.queue_outbox(
+                    state::OutboxKind::Edit {
+                        name: name.to_string(),
+                    },
+                    text,
+                    wait,
+                );
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Save a reply or edit to send once reddit's rate limit wait is over. A queued edit of the
+    /// same comment is replaced.
+    fn queue_outbox(&mut self, kind: state::OutboxKind, text: &str, wait: Option<Duration>) {
+        let wait = wait.unwrap_or(OUTBOX_DEFAULT_WAIT);
+        println!("Rate limited, queueing {:?} for {:?}", kind, wait);
+        self.state.outbox.retain(|item| item.kind != kind);
+        self.state.outbox.push(state::OutboxItem {
+            kind,
+            text: text.to_string(),
+            not_before_utc: now_utc() + wait.as_secs_f64(),
+        });
+        self.save_state();
+    }
+
+    /// Send the queued replies and edits whose wait is over. Ones that are rate limited again go
+    /// back in the queue.
+    fn flush_outbox(&mut self) {
+        let now_utc = now_utc();
+        while let Some(idx) = self
+            .state
+            .outbox
+            .iter()
+            .position(|item| item.not_before_utc <= now_utc)
+        {
+            // Leave it for whichever instance leads.
+            if !self.confirm_leader() {
+                return;
+            }
+            let item = self.state.outbox.remove(idx);
+            self.save_state();
+            let _ = match &item.kind {
+                state::OutboxKind::Reply {
+                    parent_name,
+                    link_id,
+                } => self.post_reply(parent_name, link_id.as_deref(), &item.text),
+                state::OutboxKind::Edit { name } => self.edit(name, &item.text),
+            };
+        }
+    }
+
+    /// How long until the next queued reply or edit can be sent.
+    fn until_outbox_due(&self) -> Option<Duration> {
+        let now_utc = now_utc();
+        self.state
+            .outbox
+            .iter()
+            .map(|item| item.not_before_utc)
+            .fold(None, |min: Option<f64>, t| {
+                Some(min.map_or(t, |min| min.min(t)))
+            })
+            .map(|t| Duration::from_secs_f64((t - now_utc).max(0.0)))
+    }
+
+    /// Find out whether the replies with unknown outcomes were created, from the bot's user
+    /// listing or the parent's replies. Either way they are never posted again. Intents are left
+    /// for INTENT_SETTLE_SECS first so the listings can catch up.
+    fn reconcile_intents(&mut self) {
+        let now_utc = now_utc();
+        let settled: Vec<state::Intent> = self
+            .state
+            .intents
+            .iter()
+            .filter(|i| now_utc - i.created_utc >= INTENT_SETTLE_SECS)
+            .cloned()
+            .collect();
+        if settled.is_empty() || self.load_comments_made().is_err() {
+            return;
+        }
+        for intent in settled {
+            let mut created = self
+                .comments_made
+                .iter()
+                .any(|c| c.parent_name == intent.parent_name);
+            if !created {
+                if let Some(link_id) = &intent.link_id {
+                    let link_id = strip_type(link_id);
+                    created = match self
+                        .call_retry(&format!("get_comment_tree({:?})", link_id), |app| {
+                            app.get_comment_tree(link_id)
+                        }) {
+                        Ok(tree) => find_comment(&tree, strip_type(&intent.parent_name))
+                            .map_or(false, |c| {
+                                c.replies.children.iter().any(|r| r.author == self.username)
+                            }),
+                        Err(error::Error::NotFound(_)) => false,
+                        // Try again next time.
+                        Err(_) => continue,
+                    };
+                }
+            }
+            if created {
+                println!("Reply to {} was created", intent.parent_name);
+            } else {
+                log::warn!(
+                    "Reply to {} doesn't seem to exist; not trying again",
+                    intent.parent_name
+                );
+            }
+            self.state
+                .intents
+                .retain(|i| i.parent_name != intent.parent_name);
+        }
+        self.save_state();
+    }
+
+    /// Fetch the held comments whose grace period is over and reply to those that are still
+    /// problematic. Comments that are gone are dropped.
+    fn check_pending(&mut self) {
+        let now_utc = now_utc();
+        let due: Vec<String> = self
+            .state
+            .pending
+            .iter()
+            .filter(|p| p.due_utc <= now_utc)
+            .map(|p| p.name.clone())
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+        let mut comments = match self.fetch_info(&due) {
+            Ok(x) => x,
+            // Try them again next time.
+            Err(_) => return,
+        };
+        self.state.pending.retain(|p| !due.contains(&p.name));
+        for name in &due {
+            if let Some(comment) = comments.remove(name).filter(|c| !is_deleted_comment(c)) {
+                self.on_new_comment(comment, false);
+            }
+        }
+        self.save_state();
+    }
+
+    /// How long until the next held comment is due.
+    fn until_pending_due(&self) -> Option<Duration> {
+        let now_utc = now_utc();
+        self.state
+            .pending
+            .iter()
+            .map(|p| p.due_utc)
+            .fold(None, |min: Option<f64>, due| {
+                Some(min.map_or(due, |min| min.min(due)))
+            })
+            .map(|due| Duration::from_secs_f64((due - now_utc).max(0.0)))
+    }
+
+    /// Fetch the comments due to be revisited and check the ones that were edited again.
+    fn check_revisits(&mut self) {
+        self.last_revisit_check = Some(Instant::now());
+        let now_utc = now_utc();
+        // on_new_comment would ignore them anyway.
+        self.revisits
+            .expire(now_utc - self.config.max_age_secs as f64);
+        let due = self.revisits.due(now_utc);
+        let mut comments = match self.fetch_info(&due) {
+            Ok(x) => x,
+            // Try them again next time.
+            Err(_) => return,
+        };
+        for name in &due {
+            let comment = comments.remove(name).filter(|c| !is_deleted_comment(c));
+            if self
+                .revisits
+                .fetched(name, comment.as_ref().map(edited_utc))
+            {
+                if let Some(comment) = comment {
+                    println!("Checking edited comment {}", name);
+                    self.on_new_comment(comment, true);
+                }
+            }
+        }
+    }
+
+    /// Try the queued comments that were over a rate cap again. Comments still over a cap stay
+    /// queued, and on_new_comment drops ones that got too old.
+    fn retry_over_cap(&mut self) {
+        let queued = std::mem::take(&mut self.over_cap_queue);
+        for comment in queued {
+            if self
+                .state
+                .reply_log
+                .exceeded(&self.config.rate_caps, &comment.subreddit, &comment.link_id)
+                .is_some()
+            {
+                self.over_cap_queue.push_back(comment);
+            } else {
+                self.on_new_comment(comment, false);
+            }
+        }
+    }
+
+    /// Check the comments the bot told authors or moderators about privately. When an author fixes
+    /// one, they get a follow-up message, since there is no reply to edit. Reports can't be
+    /// taken back.
+    fn check_private_deliveries(&mut self) {
+        let now_utc = now_utc();
+        let window = self.config.recheck_window_secs as f64;
+        self.state
+            .private_deliveries
+            .retain(|d| now_utc - d.created_utc <= window);
+        let names: Vec<String> = self
+            .state
+            .private_deliveries
+            .iter()
+            .map(|d| d.parent_name.clone())
+            .collect();
+        let parents = match self.fetch_info(&names) {
+            Ok(x) => x,
+            // Check them again next time.
+            Err(_) => {
+                self.save_state();
+                return;
+            }
+        };
+        for name in &names {
+            let parent = parents.get(name);
+            // Nothing to follow up on if it's gone.
+            let done = match parent {
+                None => true,
+                Some(p) if is_deleted_comment(p) => true,
+                Some(p) => !contains_problematic_fenced_block(&normalize_body(&p.body)),
+            };
+            if !done {
+                continue;
+            }
+            let idx = match self
+                .state
+                .private_deliveries
+                .iter()
+                .position(|d| &d.parent_name == name)
+            {
+                Some(x) => x,
+                None => continue,
+            };
+            let d = self.state.private_deliveries[idx].clone();
+            if parent.map_or(false, |p| !is_deleted_comment(p))
+                && d.delivery == config::Delivery::Pm
+            {
+                let body = format!(
+                    "Thanks for editing [your comment](https://old.reddit.com{})! It renders \
+                     correctly in old reddit now.",
+                    EscapeMarkdownLink(&d.permalink)
+                );
+                println!("Following up with u/{}: {}", d.author, body);
+                // Kept until the follow-up is sent, e.g. after a dry run or the kill switch ends.
+                if self.dry_run() {
+                    println!("Dry run: not messaging");
+                    continue;
+                }
+                if !self.confirm_leader() {
+                    continue;
+                }
+                // Don't retry. The message may have been sent even if the request failed.
+                let _ = self.call(&format!("message({:?})", d.author), |app| {
+                    app.message(&d.author, "Your comment is fixed", &body)
+                });
+            }
+            self.state.private_deliveries.remove(idx);
+        }
+        self.save_state();
+    }
+
+    /// Check each comment this bot has made. This bot checks if the parent comment has been
+    /// remediated, broken again, or deleted, and then edits its own comment to match.
+    fn check_comments_made(&mut self) {
+        let now_utc = now_utc();
+        let to_check: Vec<usize> = (0..self.comments_made.len())
+            .filter(|idx| {
+                let comment_made = &self.comments_made[*idx];
+                // Authors rarely come back to old comments, so stop spending requests on them.
+                comment_made.state != ReplyState::ParentDeleted
+                    && comment_made.state != ReplyState::Summoned
+                    && now_utc - comment_made.created_utc <= self.config.recheck_window_secs as f64
+            })
+            .collect();
+        self.last_comments_made_check = Some(Instant::now());
+        let names: Vec<String> = to_check
+            .iter()
+            .map(|idx| self.comments_made[*idx].parent_name.clone())
+            .collect();
+        let mut parents = match self.fetch_info(&names) {
+            Ok(x) => x,
+            // Check them again next time.
+            Err(_) => return,
+        };
+        // Forget parents that are no longer checked or were found again.
+        self.parent_misses
+            .retain(|name, _| names.contains(name) && !parents.contains_key(name));
+        for idx in to_check {
+            let parent_name = self.comments_made[idx].parent_name.clone();
+            if let Some(parent_comment) = parents.remove(&parent_name) {
+                self.update_comment_made(idx, Some(&parent_comment));
+                continue;
+            }
+            let misses = {
+                let misses = self.parent_misses.entry(parent_name.clone()).or_insert(0);
+                *misses += 1;
+                *misses
+            };
+            // Loading the whole thread is expensive, so only look there the first time.
+            if misses == 1 {
+                if let Ok(Some(parent_comment)) = self.find_parent_in_tree(idx) {
+                    self.parent_misses.remove(&parent_name);
+                    self.update_comment_made(idx, Some(&parent_comment));
+                    continue;
+                }
+            }
+            // Not finding the parent once isn't proof that it was deleted, but it won't come back
+            // after this many tries.
+            if misses >= PARENT_MISSING_LIMIT {
+                println!(
+                    "Parent {} of {} is missing, treating it as deleted",
+                    parent_name, self.comments_made[idx].name
+                );
+                self.update_comment_made(idx, None);
+            }
+        }
+    }
+
+    /// Look for the parent of a comment made in the whole comment tree. This is the fallback the
+    /// first time /api/info doesn't return the parent. Returns Ok(None) if the parent isn't in the tree or the
+    /// whole thread is gone, or if the tree only has the parent as deleted, since only /api/info is
+    /// trusted to say that.
+    fn find_parent_in_tree(
+        &mut self,
+        idx: usize,
+    ) -> Result<Option<orca::data::Comment>, error::Error> {
+        let link_id = self.comments_made[idx].link_id.clone();
+        let res = self.call_retry(&format!("get_comment_tree({:?})", link_id), |app| {
+            app.get_comment_tree(strip_type(&link_id))
+        });
+        match res {
+            Ok(tree) => {
+                let parent_name = &self.comments_made[idx].parent_name;
+                Ok(find_comment(&tree, strip_type(parent_name))
+                    .filter(|c| !is_deleted_comment(c))
+                    .cloned())
+            }
+            Err(error::Error::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Move a comment made to its next state given its current parent comment, editing or deleting
+    /// it as necessary. A parent of None is gone.
+    fn update_comment_made(&mut self, idx: usize, parent_comment: Option<&orca::data::Comment>) {
+        let comment_made = &self.comments_made[idx];
+        let name = comment_made.name.clone();
+        let parent_deleted = parent_comment.map_or(true, is_deleted_comment);
+        let parent_problematic = parent_comment.map_or(false, |c| {
+            contains_problematic_fenced_block(&normalize_body(&c.body))
+        });
+        let next = match comment_made.state.next(parent_deleted, parent_problematic) {
+            Some(x) => x,
+            None => return,
+        };
+        println!(
+            "Should update reply {} to {} in {} ({:?} -> {:?})",
+            comment_made.name,
+            comment_made.parent_name,
+            comment_made.link_id,
+            comment_made.state,
+            next
+        );
+        if next == ReplyState::ParentDeleted
+            && self.config.orphan_action == config::OrphanAction::Delete
+        {
+            // The state only moves on once the reply is deleted, so it's still deleted after a
+            // dry run or the kill switch ends.
+            if self.dry_run() {
+                println!("Dry run: not deleting");
+                return;
+            }
+            if !self.confirm_leader() {
+                return;
+            }
+            match self.call_retry(&format!("delete({:?})", name), |app| app.delete(&name)) {
+                // Somebody else already deleted it.
+                Ok(_) | Err(error::Error::NotFound(_)) => (),
+                Err(_) => return,
+            }
+            self.comments_made[idx].state = next;
+            return;
+        }
+        let mut new_reply = String::new();
+        write_reply_in_state(
+            &mut new_reply,
+            next,
+            parent_comment,
+            self.config.inline_fix_max_len,
+        );
+        println!("{}", new_reply);
+        if self.dry_run() {
+            println!("Dry run: not editing");
+            return;
+        }
+        if !self.confirm_leader() || self.edit(&name, &new_reply).is_err() {
+            return;
+        }
+        self.comments_made[idx].state = next;
+    }
+
+    // Run the main processing loop.
     fn process(&mut self) {
-        let mut error_mode = 0;
         loop {
             if let Some(last_new_comment) = self.last_new_comment {
                 // If we haven't seen a new comment in a while, increase logging verbosity
                 // (error_mode).
                 let minutes = last_new_comment.elapsed().as_secs() as f64 / 60.0;
                 if minutes > 90.0 {
-                    if error_mode != 3 {
-                        error_mode = 3;
-                        log::error!("Set error mode {}", error_mode);
+                    if self.error_mode != 3 {
+                        self.error_mode = 3;
+                        log::error!("Set error mode {}", self.error_mode);
                         log::set_max_level(log::LevelFilter::Trace);
                     }
                 } else if minutes > 60.0 {
-                    if error_mode != 2 {
-                        error_mode = 2;
-                        log::error!("Set error mode {}", error_mode);
+                    if self.error_mode != 2 {
+                        self.error_mode = 2;
+                        log::error!("Set error mode {}", self.error_mode);
                         log::set_max_level(log::LevelFilter::Debug);
                     }
                 } else if minutes > 30.0 {
-                    if error_mode != 1 {
-                        error_mode = 1;
-                        log::error!("Set error mode {}", error_mode);
+                    if self.error_mode != 1 {
+                        self.error_mode = 1;
+                        log::error!("Set error mode {}", self.error_mode);
                         log::set_max_level(log::LevelFilter::Info);
                     }
                 }
             }
-            self.refresh();
-            loop {
-                // Process comments in chronological order. Each subreddit cache is sorted, so we
-                // just need to pick the cache with the oldest first comment.
-                let mut created_utc = None;
-                let mut min_idx = None;
-                for (idx, cache) in self.caches.iter().enumerate() {
-                    if let Some(c) = cache.front() {
-                        if created_utc.is_none() || c.created_utc < created_utc.unwrap() {
-                            min_idx = Some(idx);
-                            created_utc = Some(c.created_utc);
-                        }
-                    }
+            self.check_kill_switch();
+            let was_standby = self.standby();
+            if let Some(leader) = &mut self.leader {
+                leader.check();
+            }
+            if was_standby && !self.standby() {
+                // The old leader may have posted since these were loaded.
+                self.comments_made_dirty = true;
+                self.take_over_state();
+            }
+            if self.standby() || self.paused {
+                // Held comments aren't checked while standing by or paused, so drop those too old
+                // to reply to by the time they could be.
+                let min_due_utc = now_utc() - self.config.max_age_secs as f64;
+                self.state.pending.retain(|p| p.due_utc >= min_due_utc);
+            }
+            // Things that post or take work from the other instances.
+            let active = !self.paused && !self.standby();
+            self.refresh_comments_made();
+            let recheck_interval = Duration::from_secs(5 * 60);
+            let inbox_interval = Duration::from_secs(self.config.inbox_interval_secs);
+            // Wake up at least once a minute, e.g. to notice being resumed.
+            let mut timeout = Duration::from_secs(60);
+            if active {
+                timeout = timeout.min(until_due(self.last_comments_made_check, recheck_interval));
+            }
+            if !self.standby() {
+                timeout = timeout.min(until_due(self.last_inbox_check, inbox_interval));
+            }
+            if !self.over_cap_queue.is_empty() {
+                timeout = timeout.min(OVER_CAP_RETRY_INTERVAL);
+            }
+            if !self.paused && !self.revisits.is_empty() {
+                timeout = timeout.min(until_due(self.last_revisit_check, REVISIT_CHECK_INTERVAL));
+            }
+            if !self.paused && !self.dry_run() {
+                if let Some(until_outbox_due) = self.until_outbox_due() {
+                    timeout = timeout.min(until_outbox_due);
                 }
-                if let Some(min_idx) = min_idx {
-                    let comment = self.caches[min_idx].pop_front().unwrap();
+            }
+            // Held comments aren't checked while paused or standing by.
+            if let Some(until_pending_due) = self.until_pending_due().filter(|_| active) {
+                timeout = timeout.min(until_pending_due);
+            }
+            if let Some(leader) = &self.leader {
+                timeout = timeout.min(leader.interval());
+            }
+            if self.config.kill_switch.is_some() {
+                timeout = timeout.min(until_due(self.last_kill_switch_check, KILL_SWITCH_INTERVAL));
+            }
+            // The poller sends comments in chronological order.
+            match self.new_comments.recv_timeout(timeout) {
+                Ok(comments) => {
                     self.last_new_comment = Some(Instant::now());
-                    if error_mode != 0 {
+                    if self.error_mode != 0 {
                         log::error!("Resetting error mode");
                         log::set_max_level(log::LevelFilter::Warn);
-                        error_mode = 0;
+                        self.error_mode = 0;
                     }
-                    self.on_new_comment(comment);
-                } else {
-                    break;
+                    let last_comment = comments.last().map(|c| state::LastComment {
+                        name: c.name.clone(),
+                        created_utc: c.created_utc,
+                    });
+                    for comment in comments {
+                        self.last_new_comment_by_subreddit
+                            .insert(comment.subreddit.to_lowercase(), Instant::now());
+                        // A batch can hold many comments after downtime, so the switch has to
+                        // stop the rest of it.
+                        self.check_kill_switch();
+                        if comment.author != self.username {
+                            self.revisits.add(
+                                &comment.name,
+                                comment.created_utc,
+                                edited_utc(&comment),
+                            );
+                        }
+                        self.on_new_comment(comment, true);
+                    }
+                    // Saved once per batch rather than per comment, since each save syncs the file.
+                    self.state.last_comment = last_comment;
+                    self.save_state();
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    log::error!("Poller stopped");
+                    return;
                 }
             }
-            if self
-                .last_comments_made_check
-                .map(|i| i < Instant::now() - Duration::from_secs(5 * 60))
-                .unwrap_or(true)
+            if active
+                && until_due(self.last_comments_made_check, recheck_interval)
+                    == Duration::from_secs(0)
             {
+                self.reconcile_intents();
                 self.check_comments_made();
+                self.check_private_deliveries();
+            }
+            // Reading the inbox marks messages read, so only the leader does it.
+            if !self.standby()
+                && until_due(self.last_inbox_check, inbox_interval) == Duration::from_secs(0)
+            {
+                self.check_inbox();
+            }
+            if active {
+                self.check_pending();
+            }
+            // Queued items wait out a dry run.
+            if !self.paused && !self.dry_run() {
+                self.flush_outbox();
+            }
+            if !self.paused
+                && until_due(self.last_revisit_check, REVISIT_CHECK_INTERVAL)
+                    == Duration::from_secs(0)
+            {
+                self.check_revisits();
+            }
+            if active
+                && until_due(self.last_over_cap_retry, OVER_CAP_RETRY_INTERVAL)
+                    == Duration::from_secs(0)
+            {
+                self.last_over_cap_retry = Some(Instant::now());
+                self.retry_over_cap();
             }
         }
     }
@@ -560,28 +2096,87 @@ impl std::fmt::Display for EscapeMarkdownLink<'_> {
 fn main() {
     simple_logger::init_with_level(log::Level::Trace).unwrap();
     log::set_max_level(log::LevelFilter::Warn);
-    let secret = get_pass("Reddit/old-reddit-fmt-bot/secret");
-    let id = get_pass("Reddit/old-reddit-fmt-bot/id");
-    let password = get_pass("Misc/reddit.com/old-reddit-fmt-bot");
-    let mut app = orca::App::new("old fmt experiment", VERSION, "singron").unwrap();
-    let username = "old-reddit-fmt-bot";
-    app.authorize_script(&id, &secret, username, &password)
-        .unwrap();
-    // Remove secrets from memory
-    drop(secret);
-    drop(id);
-    drop(password);
-
-    let mut bot = Bot::new(&app, username, &["programming", "rust", "NixOS"]);
-    loop {
-        match bot.load_comments_made() {
-            Ok(_) => break,
-            Err(e) => {
-                println!("Error in load_comments_made: {}", e);
-                continue;
-            }
+    let config = match std::env::args().nth(1) {
+        Some(path) => config::Config::load(&path).unwrap_or_else(|e| {
+            log::error!("Error loading config {:?}: {}", path, e);
+            std::process::exit(1);
+        }),
+        None => config::Config::default(),
+    };
+    let state = match &config.state_path {
+        Some(path) => state::State::load(path).unwrap_or_else(|e| {
+            log::error!("Error loading state {:?}: {}", path, e);
+            std::process::exit(1);
+        }),
+        None => {
+            log::warn!("No state_path is set, so the bot won't post replies or deliveries");
+            state::State::default()
         }
+    };
+    if cfg!(not(unix)) && config.leader_lock.is_some() {
+        log::error!("leader_lock is only supported on unix");
+        std::process::exit(1);
     }
+    if let (Some(lock), Some(state_path)) = (&config.leader_lock, &config.state_path) {
+        let dir = |path: &str| std::path::Path::new(path).parent().map(|p| p.to_owned());
+        if dir(lock) != dir(state_path) {
+            log::warn!(
+                "state_path isn't next to leader_lock, so a standby may not find the leader's state"
+            );
+        }
+    }
+    let credentials = match config.credentials.load() {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("Error loading credentials: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let username = "old-reddit-fmt-bot";
+    // Each thread gets its own client.
+    let new_app = || -> Result<orca::App, error::Error> {
+        let mut app = orca::App::new("old fmt experiment", VERSION, "singron")
+            .map_err(error::Error::from_reddit)?;
+        app.authorize_script(
+            &credentials.id,
+            &credentials.secret,
+            username,
+            &credentials.password,
+        )
+        .map_err(error::Error::from_reddit)?;
+        Ok(app)
+    };
+    let (app, poll_app) = match new_app().and_then(|app| Ok((app, new_app()?))) {
+        Ok(x) => x,
+        Err(e) => abort(&e),
+    };
+    // Zeroize the secrets.
+    drop(credentials);
+
+    let limiter = Arc::new(RateLimiter::new(Duration::from_millis(
+        config.request_interval_ms,
+    )));
+    let (new_comments_tx, new_comments) = mpsc::channel();
+    let subreddits = Arc::new(Mutex::new(config.subreddits.clone()));
+    poll::Poller::new(
+        poll_app,
+        subreddits.clone(),
+        state.last_comment.clone(),
+        Duration::from_secs(config.max_age_secs),
+        limiter.clone(),
+        new_comments_tx,
+    )
+    .spawn();
+
+    let mut bot = Bot::new(
+        &app,
+        username,
+        config,
+        state,
+        new_comments,
+        subreddits,
+        limiter,
+    );
     bot.process();
 }
 
@@ -590,47 +2185,115 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_fenced_block() {
-        let tests: &[(bool, &'static str)] = &[
-            (false, ""),
-            (false, "hi\n"),
-            (false, "inline `codeblock`\n"),
-            (false, "`code`\n"),
-            (false, "    hi\n"),
-            (false, ">     hi\n"),
-            // Some single line code blocks look close enough when interpreted as an inline code
-            // span.
-            (false, "```\nhi\n```"),
-            (false, "```\nx x\n```"),
-            (false, "hi\n\n```\nhi\n```\n\nhi"),
-            (false, "1.  ```\n    hi\n    ```"),
-            (false, "1.  ```\n    hi\n    ```\n\n    hi"),
-            (false, "> ```\n> hi\n> ```"),
-            (false, "```\nx\n```\n\n```\nx\n```"),
-            (true, "x\n```\nhi\n```"),
-            (true, "```rust\nhi\n```"),
-            (true, "> ```\n> hi\n> hi\n> ```"),
-            (true, "1.  hi\n    ```\n    hi\n    ```\n"),
-            (true, "```\n&\n&\n```"),
-            (true, "```\n\tx\n```"),
-            (true, "```\n x\n```"),
-            (true, "```\nx \n```"),
-            (true, "```\nx\tx\n```"),
-            (true, "```\nx  x\n```"),
-            (true, "```\nx\n```\n\n```\nx  x\n```"),
-            (true, "```\nx  x\n```\n\n```\nx\n```"),
+    fn test_write_inline_fix() {
+        let mut out = String::new();
+        assert!(write_inline_fix(
+            &mut out,
+            "x\n```\nfn f() {\n\tx\n}\n```",
+            1000
+        ));
+        assert_eq!(
+            out,
+            "\n\n---\n\nHere is your code indented with 4 spaces, ready to paste:\n\n    \
+             fn f() {\n    \tx\n    }"
+        );
+
+        let mut out = String::new();
+        assert!(write_inline_fix(
+            &mut out,
+            "```rust\na\n```\n\n```\nb  b\n```",
+            1000
+        ));
+        assert_eq!(
+            out,
+            "\n\n---\n\nHere is your code indented with 4 spaces, ready to paste:\
+             \n\nBlock 1:\n\n    a\n\nBlock 2:\n\n    b  b"
+        );
+
+        // Blocks that look fine aren't included.
+        let mut out = String::new();
+        assert!(write_inline_fix(
+            &mut out,
+            "```\nok\n```\n\n```rust\na\n```",
+            1000
+        ));
+        assert!(!out.contains("ok"));
+
+        let mut out = String::new();
+        assert!(!write_inline_fix(&mut out, "```rust\na\nb\n```", 70));
+        assert!(!write_inline_fix(&mut out, "```\nok\n```", 1000));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_write_conversion() {
+        let mut out = String::new();
+        write_conversion(&mut out, "Look:\n```\nfn f() {}\n```");
+        assert!(out.starts_with("Here is your markdown"), "{}", out);
+        assert!(out.contains("code block below:\n\n    Look:\n    \n        fn f() {}\n\n"));
+        assert!(out.contains("\n* Block 1 doesn't have a blank line before it"));
+
+        // Indenting counts toward the limit.
+        let mut out = String::new();
+        let long = format!("```\n{}```", "x\n".repeat(SUMMONED_FIX_MAX_LEN / 8));
+        write_conversion(&mut out, &long);
+        assert!(out.starts_with("Your message is too long"), "{}", out);
+
+        let mut out = String::new();
+        write_conversion(&mut out, "    fn f() {}");
+        assert!(out.starts_with("I didn't find anything"), "{}", out);
+    }
+
+    #[test]
+    fn test_is_bot_name() {
+        assert!(is_bot_name("AutoModerator"));
+        assert!(is_bot_name("old-reddit-fmt-bot"));
+        assert!(is_bot_name("RemindMeBot"));
+        assert!(is_bot_name("some_bot"));
+        assert!(!is_bot_name("singron"));
+        assert!(!is_bot_name("bottomless"));
+    }
+
+    #[test]
+    fn test_mentions_formatting() {
+        assert!(mentions_formatting(
+            "Use four spaces instead of backticks for old reddit"
+        ));
+        assert!(mentions_formatting("Please indent your code by 4 spaces."));
+        assert!(!mentions_formatting("Have you tried cargo clippy?"));
+        assert!(!mentions_formatting("What does this code block do?"));
+    }
+
+    #[test]
+    fn test_reply_state() {
+        use ReplyState::*;
+        for state in &[Flagged, Fixed, Reflagged, ParentDeleted, Summoned] {
+            let mut body = state.prefix().to_string();
+            body.push_str("Your comment uses fenced code blocks");
+            assert_eq!(Some(*state), ReplyState::from_body(&body));
+        }
+        assert_eq!(None, ReplyState::from_body("Good bot"));
+        // The inline fix quotes the author's code, which can contain anything.
+        let mut body = "Your comment uses fenced code blocks".to_string();
+        write_inline_fix(&mut body, "```\n// EDIT: fixed\nx\n```", 100);
+        assert_eq!(Some(Flagged), ReplyState::from_body(&body));
+        // (state, parent_deleted, parent_problematic, next)
+        let tests: &[(ReplyState, bool, bool, Option<ReplyState>)] = &[
+            (Flagged, false, true, None),
+            (Flagged, false, false, Some(Fixed)),
+            (Fixed, false, false, None),
+            (Fixed, false, true, Some(Reflagged)),
+            (Reflagged, false, true, None),
+            (Reflagged, false, false, Some(Fixed)),
+            (Flagged, true, false, Some(ParentDeleted)),
+            (Fixed, true, false, Some(ParentDeleted)),
+            (ParentDeleted, true, false, None),
+            (ParentDeleted, false, true, None),
+            (Summoned, true, false, None),
+            (Summoned, false, false, None),
         ];
-        for (want, body) in tests {
-            let got = contains_problematic_fenced_block(body);
-            if *want != got {
-                let arena = comrak::Arena::new();
-                let ast = comrak::parse_document(&arena, body, &comrak_opts());
-                println!("AST: {:#?}", ast);
-                panic!(
-                    "Expected {:?} for contains_problematic_fenced_block({:?})",
-                    *want, body
-                );
-            }
+        for (state, deleted, problematic, next) in tests {
+            assert_eq!(*next, state.next(*deleted, *problematic), "{:?}", state);
         }
     }
 
@@ -0,0 +1,369 @@
+use std::collections::VecDeque;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{Action, Error};
+use crate::state::LastComment;
+use crate::{abort, now_utc, Backoff, RateLimiter};
+
+/// The number of comments requested per page. This is the most reddit allows.
+const PAGE_SIZE: usize = 100;
+
+/// Reddit listings don't go back further than 1000 items.
+const MAX_PAGES: usize = 10;
+
+/// The number of recently sent comment names to remember. Comments created in the same second as
+/// the last comment are checked against these to avoid sending them twice.
+const RECENT_NAMES: usize = 100;
+
+/// Poller fetches new comments from all subreddits on its own thread and sends them to the bot in
+/// chronological order, one batch per poll.
+pub struct Poller {
+    app: orca::App,
+    /// The subreddits to poll. The bot can change these while running.
+    subreddits: Arc<Mutex<Vec<String>>>,
+    /// The newest comment sent. Polling pages back until it reaches this comment.
+    last_comment: Option<LastComment>,
+    recent_comment_names: VecDeque<String>,
+    max_age: Duration,
+    limiter: Arc<RateLimiter>,
+    backoff: Backoff,
+    new_comments: mpsc::Sender<Vec<orca::data::Comment>>,
+}
+
+impl Poller {
+    pub fn new(
+        app: orca::App,
+        subreddits: Arc<Mutex<Vec<String>>>,
+        last_comment: Option<LastComment>,
+        max_age: Duration,
+        limiter: Arc<RateLimiter>,
+        new_comments: mpsc::Sender<Vec<orca::data::Comment>>,
+    ) -> Poller {
+        Poller {
+            app,
+            subreddits,
+            recent_comment_names: last_comment.iter().map(|c| c.name.clone()).collect(),
+            last_comment,
+            max_age,
+            limiter,
+            backoff: Backoff { fails: 0 },
+            new_comments,
+        }
+    }
+
+    /// Start polling on a new thread.
+    pub fn spawn(self) -> std::thread::JoinHandle<()> {
+        std::thread::Builder::new()
+            .name("poll".to_string())
+            .spawn(move || self.run())
+            .unwrap()
+    }
+
+    /// Poll until the bot stops receiving comments.
+    fn run(mut self) {
+        let min_refresh = Duration::from_secs(5);
+        loop {
+            let start = Instant::now();
+            if self.poll().is_err() {
+                return;
+            }
+            let e = start.elapsed();
+            if e < min_refresh {
+                std::thread::sleep(min_refresh - e);
+            }
+        }
+    }
+
+    /// Fetch one page of recent comments, newest first, retrying until it succeeds. subreddit may
+    /// combine several subreddits with "+".
+    fn fetch(
+        &mut self,
+        subreddit: &str,
+        after: Option<&str>,
+    ) -> orca::data::Listing<orca::data::Comment> {
+        loop {
+            self.limiter.wait();
+            let res = match after {
+                None => self
+                    .app
+                    .get_recent_comments(subreddit, Some(PAGE_SIZE as _), None),
+                Some(after) => {
+                    self.app
+                        .get_recent_comments_after(subreddit, Some(PAGE_SIZE as _), after)
+                }
+            };
+            match res {
+                Ok(res) => {
+                    self.backoff.ok();
+                    return res;
+                }
+                Err(e) => {
+                    let e = Error::from_reddit(e);
+                    log::warn!(
+                        "Error get_recent_comments({:?}, after={:?}): {}",
+                        subreddit,
+                        after,
+                        e
+                    );
+                    // Polling is all this thread does, so keep trying unless it's hopeless.
+                    match e {
+                        _ if e.action() == Action::Abort => abort(&e),
+                        Error::RateLimit {
+                            wait: Some(wait), ..
+                        } => std::thread::sleep(wait),
+                        _ => self.backoff.fail_wait(),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Fetch the comments made since the last poll and send them. If the newest page is full,
+    /// older pages are fetched until they reach the last comment sent or the max age, so busy
+    /// subreddits and downtime don't leave gaps. Gaps reddit's listing doesn't go back far enough
+    /// to fill are logged. Returns Err if the receiver is gone.
+    fn poll(&mut self) -> Result<(), mpsc::SendError<Vec<orca::data::Comment>>> {
+        // Reddit accepts subreddits joined with "+" as one combined subreddit.
+        let subreddit = self.subreddits.lock().unwrap().join("+");
+        if subreddit.is_empty() {
+            return Ok(());
+        }
+        let min_created_utc = now_utc() - self.max_age.as_secs_f64();
+        let last_comment = self.last_comment.clone();
+        let recent_comment_names = std::mem::take(&mut self.recent_comment_names);
+        let walk = walk_pages(
+            |after| self.fetch(&subreddit, after).children,
+            last_comment.as_ref(),
+            &recent_comment_names,
+            min_created_utc,
+        );
+        self.recent_comment_names = recent_comment_names;
+        let mut new_comments = walk.new_comments;
+        let backfilled = new_comments.len() - walk.first_page_len;
+        if backfilled > 0 {
+            log::warn!(
+                "Backfilled {} comments in r/{} beyond the newest page",
+                backfilled,
+                subreddit
+            );
+        }
+        if walk.gap {
+            log::error!(
+                "Skipped comments in r/{}: {} pages didn't go back to the last comment checked",
+                subreddit,
+                MAX_PAGES
+            );
+        }
+        let newest = match new_comments.first() {
+            Some(c) => LastComment {
+                name: c.name.clone(),
+                created_utc: c.created_utc,
+            },
+            None => return Ok(()),
+        };
+        self.last_comment = Some(newest);
+        new_comments.reverse();
+        for c in &new_comments {
+            self.recent_comment_names.push_front(c.name.clone());
+        }
+        self.recent_comment_names.truncate(RECENT_NAMES);
+        self.new_comments.send(new_comments)
+    }
+}
+
+/// A comment in a listing, as far as paging through it is concerned.
+trait Listed {
+    fn name(&self) -> &str;
+    fn created_utc(&self) -> f64;
+}
+
+impl Listed for orca::data::Comment {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn created_utc(&self) -> f64 {
+        self.created_utc
+    }
+}
+
+/// The comments found by walk_pages.
+struct Walk<T> {
+    /// Newest first.
+    new_comments: Vec<T>,
+    /// How many of new_comments were on the newest page.
+    first_page_len: usize,
+    /// Whether MAX_PAGES full pages didn't reach the last comment or the max age, so the comments
+    /// between them were skipped. A listing that runs out before then has nothing older to skip.
+    gap: bool,
+}
+
+/// Page back through a listing, newest first, until reaching the last comment sent or
+/// min_created_utc. fetch_page returns the page after the given name, or the newest page. Comments
+/// in recent_names were already sent and are left out.
+fn walk_pages<T: Listed>(
+    mut fetch_page: impl FnMut(Option<&str>) -> Vec<T>,
+    last_comment: Option<&LastComment>,
+    recent_names: &VecDeque<String>,
+    min_created_utc: f64,
+) -> Walk<T> {
+    let mut walk = Walk {
+        new_comments: Vec::new(),
+        first_page_len: 0,
+        gap: false,
+    };
+    let mut after: Option<String> = None;
+    for page_idx in 0..MAX_PAGES {
+        let page = fetch_page(after.as_deref());
+        let full = page.len() >= PAGE_SIZE;
+        after = page.last().map(|c| c.name().to_string());
+        let mut reached = false;
+        for c in page {
+            let reached_last = last_comment.map_or(false, |last| {
+                c.name() == last.name || c.created_utc() < last.created_utc
+            });
+            if reached_last || c.created_utc() < min_created_utc {
+                reached = true;
+                break;
+            }
+            if recent_names.iter().any(|name| name == c.name()) {
+                continue;
+            }
+            walk.new_comments.push(c);
+        }
+        if page_idx == 0 {
+            walk.first_page_len = walk.new_comments.len();
+        }
+        // Without a last comment, e.g. on the first start, just take the newest page.
+        if reached || !full || last_comment.is_none() {
+            return walk;
+        }
+    }
+    walk.gap = true;
+    walk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestComment {
+        name: String,
+        created_utc: f64,
+    }
+
+    impl Listed for TestComment {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn created_utc(&self) -> f64 {
+            self.created_utc
+        }
+    }
+
+    /// A listing of comments t1_0 to t1_{n-1}, each a second apart, split into pages newest first.
+    fn listing(n: usize) -> impl FnMut(Option<&str>) -> Vec<TestComment> {
+        move |after| {
+            let start = match after {
+                None => n,
+                Some(name) => name[3..].parse().unwrap(),
+            };
+            (0..start)
+                .rev()
+                .take(PAGE_SIZE)
+                .map(|i| TestComment {
+                    name: format!("t1_{}", i),
+                    created_utc: i as f64,
+                })
+                .collect()
+        }
+    }
+
+    fn last(i: usize) -> LastComment {
+        LastComment {
+            name: format!("t1_{}", i),
+            created_utc: i as f64,
+        }
+    }
+
+    fn names(walk: &Walk<TestComment>) -> Vec<&str> {
+        walk.new_comments.iter().map(|c| c.name()).collect()
+    }
+
+    #[test]
+    fn test_walk_pages() {
+        let recent = VecDeque::new();
+
+        // The first start only takes the newest page.
+        let walk = walk_pages(listing(250), None, &recent, 0.0);
+        assert_eq!(PAGE_SIZE, walk.new_comments.len());
+        assert_eq!("t1_249", walk.new_comments[0].name);
+        assert!(!walk.gap);
+
+        // Restarting pages back to the last comment.
+        let walk = walk_pages(listing(250), Some(&last(20)), &recent, 0.0);
+        assert_eq!(229, walk.new_comments.len());
+        assert_eq!(PAGE_SIZE, walk.first_page_len);
+        assert_eq!("t1_21", walk.new_comments.last().unwrap().name);
+        assert!(!walk.gap);
+
+        // A deleted last comment is passed by its time.
+        let gone = LastComment {
+            name: "t1_gone".to_string(),
+            created_utc: 20.5,
+        };
+        let walk = walk_pages(listing(250), Some(&gone), &recent, 0.0);
+        assert_eq!("t1_21", walk.new_comments.last().unwrap().name);
+
+        // The max age stops it too.
+        let walk = walk_pages(listing(250), Some(&last(20)), &recent, 200.0);
+        assert_eq!(50, walk.new_comments.len());
+        assert!(!walk.gap);
+
+        // The last comment is past the listing's limit.
+        let walk = walk_pages(listing(2000), Some(&last(20)), &recent, 0.0);
+        assert_eq!(MAX_PAGES * PAGE_SIZE, walk.new_comments.len());
+        assert!(walk.gap);
+
+        // The listing runs out before the last comment, so there is nothing older to miss.
+        let old = LastComment {
+            name: "t1_old".to_string(),
+            created_utc: -1.0,
+        };
+        let walk = walk_pages(listing(50), Some(&old), &recent, 0.0);
+        assert_eq!(50, walk.new_comments.len());
+        assert!(!walk.gap);
+    }
+
+    #[test]
+    fn test_walk_pages_recent_names() {
+        // Comments created in the same second as the last comment.
+        let page = || {
+            vec![
+                TestComment {
+                    name: "t1_c".to_string(),
+                    created_utc: 10.0,
+                },
+                TestComment {
+                    name: "t1_b".to_string(),
+                    created_utc: 10.0,
+                },
+                TestComment {
+                    name: "t1_a".to_string(),
+                    created_utc: 10.0,
+                },
+            ]
+        };
+        let last = LastComment {
+            name: "t1_a".to_string(),
+            created_utc: 10.0,
+        };
+        let recent: VecDeque<String> = vec!["t1_b".to_string(), "t1_a".to_string()]
+            .into_iter()
+            .collect();
+        let walk = walk_pages(|_| page(), Some(&last), &recent, 0.0);
+        assert_eq!(vec!["t1_c"], names(&walk));
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+
+use crate::now_utc;
+
+const HOUR: f64 = 60.0 * 60.0;
+const DAY: f64 = 60.0 * 60.0 * 24.0;
+
+/// Limits on how many replies the bot posts. None means no limit.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateCaps {
+    pub per_subreddit_per_hour: Option<usize>,
+    pub per_subreddit_per_day: Option<usize>,
+    /// Replies in one thread in the last day.
+    pub per_thread: Option<usize>,
+    pub global_per_hour: Option<usize>,
+    pub global_per_day: Option<usize>,
+}
+
+/// One reply posted.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Reply {
+    /// Seconds since the epoch.
+    at_utc: f64,
+    /// Lowercase.
+    subreddit: String,
+    link_id: String,
+}
+
+/// ReplyLog remembers the replies posted in the last day to enforce RateCaps. It's saved in the
+/// state so restarts don't reset the caps.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct ReplyLog {
+    /// Oldest first.
+    replies: VecDeque<Reply>,
+}
+
+impl ReplyLog {
+    /// Record a reply posted now.
+    pub fn record(&mut self, subreddit: &str, link_id: &str) {
+        self.record_at(now_utc(), subreddit, link_id);
+    }
+
+    fn record_at(&mut self, at_utc: f64, subreddit: &str, link_id: &str) {
+        self.replies.push_back(Reply {
+            at_utc,
+            subreddit: subreddit.to_lowercase(),
+            link_id: link_id.to_string(),
+        });
+    }
+
+    /// Return a description of the first cap that another reply in this subreddit and thread
+    /// would exceed, or None if it's allowed.
+    pub fn exceeded(
+        &mut self,
+        caps: &RateCaps,
+        subreddit: &str,
+        link_id: &str,
+    ) -> Option<&'static str> {
+        self.exceeded_at(now_utc(), caps, subreddit, link_id)
+    }
+
+    fn exceeded_at(
+        &mut self,
+        now_utc: f64,
+        caps: &RateCaps,
+        subreddit: &str,
+        link_id: &str,
+    ) -> Option<&'static str> {
+        while let Some(r) = self.replies.front() {
+            if now_utc - r.at_utc < DAY {
+                break;
+            }
+            self.replies.pop_front();
+        }
+        let subreddit = subreddit.to_lowercase();
+        let count = |within: f64, f: &dyn Fn(&Reply) -> bool| {
+            self.replies
+                .iter()
+                .filter(|r| now_utc - r.at_utc < within && f(r))
+                .count()
+        };
+        let over = |cap: Option<usize>, n: usize| cap.map_or(false, |cap| n >= cap);
+        let in_subreddit = |r: &Reply| r.subreddit == subreddit;
+        if over(caps.global_per_hour, count(HOUR, &|_| true)) {
+            return Some("global per hour");
+        }
+        if over(caps.global_per_day, count(DAY, &|_| true)) {
+            return Some("global per day");
+        }
+        if over(caps.per_subreddit_per_hour, count(HOUR, &in_subreddit)) {
+            return Some("per subreddit per hour");
+        }
+        if over(caps.per_subreddit_per_day, count(DAY, &in_subreddit)) {
+            return Some("per subreddit per day");
+        }
+        if over(caps.per_thread, count(DAY, &|r| r.link_id == link_id)) {
+            return Some("per thread");
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exceeded() {
+        let caps = RateCaps {
+            per_subreddit_per_hour: Some(2),
+            per_thread: Some(1),
+            global_per_day: Some(3),
+            ..Default::default()
+        };
+        let start = 1000.0;
+        let mut log = ReplyLog::default();
+        assert_eq!(None, log.exceeded_at(start, &caps, "rust", "t3_a"));
+        log.record_at(start, "rust", "t3_a");
+        assert_eq!(
+            Some("per thread"),
+            log.exceeded_at(start, &caps, "Rust", "t3_a")
+        );
+        assert_eq!(None, log.exceeded_at(start, &caps, "rust", "t3_b"));
+        log.record_at(start, "rust", "t3_b");
+        assert_eq!(
+            Some("per subreddit per hour"),
+            log.exceeded_at(start, &caps, "rust", "t3_c")
+        );
+        assert_eq!(None, log.exceeded_at(start, &caps, "NixOS", "t3_d"));
+        log.record_at(start, "NixOS", "t3_d");
+        assert_eq!(
+            Some("global per day"),
+            log.exceeded_at(start + HOUR, &caps, "programming", "t3_e")
+        );
+        // Everything ages out after a day.
+        assert_eq!(None, log.exceeded_at(start + DAY, &caps, "rust", "t3_a"));
+    }
+}
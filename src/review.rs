@@ -0,0 +1,158 @@
+use crate::error::Error;
+
+/// A reply the bot would post to a comment. In supervised mode it waits in the ReviewQueue for an
+/// operator to approve or reject it.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ReviewItem {
+    /// The comment's fullname.
+    pub name: String,
+    pub author: String,
+    pub subreddit: String,
+    pub link_id: String,
+    pub permalink: String,
+    /// The normalized body of the comment.
+    pub body: String,
+    /// What's wrong with each problematic block.
+    pub problems: Vec<String>,
+    pub reply: String,
+}
+
+/// ReviewQueue keeps each item waiting for review as a JSON file named after the comment, so they
+/// can also be read with any editor and survive restarts.
+pub struct ReviewQueue {
+    dir: std::path::PathBuf,
+}
+
+/// Check that a fullname is safe to use as a file name.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl ReviewQueue {
+    pub fn new(dir: &str) -> ReviewQueue {
+        ReviewQueue { dir: dir.into() }
+    }
+
+    fn path(&self, name: &str) -> std::path::PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        is_valid_name(name) && self.path(name).exists()
+    }
+
+    pub fn add(&self, item: &ReviewItem) -> Result<(), Error> {
+        if !is_valid_name(&item.name) {
+            return Err(Error::Parse(failure::format_err!(
+                "invalid name {:?}",
+                item.name
+            )));
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        let tmp = self.dir.join(format!("{}.json.tmp", item.name));
+        std::fs::write(&tmp, serde_json::to_vec_pretty(item)?)?;
+        std::fs::rename(&tmp, self.path(&item.name))?;
+        Ok(())
+    }
+
+    /// The names of the items waiting for review.
+    pub fn list(&self) -> Result<Vec<String>, Error> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name.ends_with(".json") {
+                names.push(file_name[..file_name.len() - ".json".len()].to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Remove an item from the queue and return it. Returns None if it isn't queued.
+    pub fn take(&self, name: &str) -> Result<Option<ReviewItem>, Error> {
+        if !is_valid_name(name) {
+            return Ok(None);
+        }
+        let path = self.path(name);
+        let contents = match std::fs::read(&path) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let item = serde_json::from_slice(&contents)?;
+        std::fs::remove_file(&path)?;
+        Ok(Some(item))
+    }
+}
+
+/// A rejected comment in the regression corpus.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CorpusCase {
+    pub name: String,
+    /// Whether the detector shouldn't have flagged the body. Other rejections, e.g. a reply that
+    /// would be unwelcome in the thread, are kept for reference but the body is still flagged.
+    pub false_positive: bool,
+    /// Why the operator rejected it.
+    pub reason: String,
+    pub body: String,
+}
+
+/// Save a rejected item to the regression corpus. Cases in corpus/negative that are false
+/// positives are checked by detect's tests to make sure they aren't flagged.
+pub fn save_negative(
+    corpus_dir: &str,
+    item: &ReviewItem,
+    false_positive: bool,
+    reason: &str,
+) -> Result<(), Error> {
+    let dir = std::path::Path::new(corpus_dir).join("negative");
+    std::fs::create_dir_all(&dir)?;
+    let case = CorpusCase {
+        name: item.name.clone(),
+        false_positive,
+        reason: reason.to_string(),
+        body: item.body.clone(),
+    };
+    std::fs::write(
+        dir.join(format!("{}.json", item.name)),
+        serde_json::to_vec_pretty(&case)?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_review_queue() {
+        let dir =
+            std::env::temp_dir().join(format!("old-reddit-fmt-bot-review-{}", std::process::id()));
+        let queue = ReviewQueue::new(&dir.to_string_lossy());
+        assert_eq!(Vec::<String>::new(), queue.list().unwrap());
+        let item = ReviewItem {
+            name: "t1_abc".to_string(),
+            author: "someone".to_string(),
+            subreddit: "rust".to_string(),
+            link_id: "t3_xyz".to_string(),
+            permalink: "/r/rust/comments/xyz/_/abc/".to_string(),
+            body: "```\nx\nx\n```".to_string(),
+            problems: vec!["multiple lines".to_string()],
+            reply: "Your comment uses fenced code blocks".to_string(),
+        };
+        queue.add(&item).unwrap();
+        assert!(queue.contains("t1_abc"));
+        assert_eq!(vec!["t1_abc"], queue.list().unwrap());
+        assert!(queue.take("../t1_abc").unwrap().is_none());
+        assert_eq!(item.body, queue.take("t1_abc").unwrap().unwrap().body);
+        assert!(!queue.contains("t1_abc"));
+        assert!(queue.take("t1_abc").unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
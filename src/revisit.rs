@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+
+/// The most comments to remember. The oldest are dropped first.
+const LIMIT: usize = 10000;
+
+/// A comment to fetch again to see if it was edited.
+struct Entry {
+    name: String,
+    created_utc: f64,
+    /// The edited timestamp last seen, if it was edited.
+    edited_utc: Option<f64>,
+    /// The index of the next interval.
+    stage: usize,
+}
+
+/// Revisits schedules comments to be fetched again at increasing intervals after they were
+/// posted, so edits that add fenced blocks are noticed.
+pub struct Revisits {
+    /// Seconds after the comment was created, in increasing order.
+    intervals: Vec<u64>,
+    /// Oldest first.
+    entries: VecDeque<Entry>,
+}
+
+impl Revisits {
+    pub fn new(intervals: Vec<u64>) -> Revisits {
+        Revisits {
+            intervals,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Schedule a comment.
+    pub fn add(&mut self, name: &str, created_utc: f64, edited_utc: Option<f64>) {
+        if self.intervals.is_empty() {
+            return;
+        }
+        if self.entries.len() >= LIMIT {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            name: name.to_string(),
+            created_utc,
+            edited_utc,
+            stage: 0,
+        });
+    }
+
+    /// The names of the comments due to be fetched at now_utc.
+    pub fn due(&self, now_utc: f64) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|e| e.created_utc + self.intervals[e.stage] as f64 <= now_utc)
+            .map(|e| e.name.clone())
+            .collect()
+    }
+
+    /// Record that a due comment was fetched, and whether it was found. Returns true if it was
+    /// edited since it was last seen. Comments that are gone or past their last interval are
+    /// forgotten.
+    pub fn fetched(&mut self, name: &str, found: Option<Option<f64>>) -> bool {
+        let idx = match self.entries.iter().position(|e| e.name == name) {
+            Some(x) => x,
+            None => return false,
+        };
+        let edited_utc = match found {
+            Some(x) => x,
+            None => {
+                self.entries.remove(idx);
+                return false;
+            }
+        };
+        let entry = &mut self.entries[idx];
+        let edited = edited_utc.is_some() && edited_utc != entry.edited_utc;
+        entry.edited_utc = edited_utc;
+        entry.stage += 1;
+        if entry.stage >= self.intervals.len() {
+            self.entries.remove(idx);
+        }
+        edited
+    }
+
+    /// Forget comments created before min_created_utc.
+    pub fn expire(&mut self, min_created_utc: f64) {
+        self.entries.retain(|e| e.created_utc >= min_created_utc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_revisits() {
+        let mut r = Revisits::new(vec![600, 3600]);
+        r.add("t1_a", 1000.0, None);
+        r.add("t1_b", 1100.0, None);
+        assert!(r.due(1599.0).is_empty());
+        assert_eq!(vec!["t1_a"], r.due(1600.0));
+
+        // Not edited, so it's scheduled for the next interval.
+        assert!(!r.fetched("t1_a", Some(None)));
+        assert_eq!(vec!["t1_b"], r.due(1700.0));
+        // Gone.
+        assert!(!r.fetched("t1_b", None));
+        assert_eq!(1, r.len());
+
+        assert_eq!(vec!["t1_a"], r.due(4600.0));
+        assert!(r.fetched("t1_a", Some(Some(4000.0))));
+        // That was the last interval.
+        assert_eq!(0, r.len());
+
+        r.add("t1_c", 5000.0, Some(5010.0));
+        assert!(!r.fetched("t1_c", Some(Some(5010.0))));
+        r.expire(5001.0);
+        assert_eq!(0, r.len());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::error::Error;
+
+/// State that should survive restarts. It's saved as JSON.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct State {
+    /// The newest comment processed.
+    pub last_comment: Option<LastComment>,
+    /// Flagged comments waiting out the grace period before the bot replies, in the order they
+    /// were flagged.
+    pub pending: Vec<Pending>,
+    /// Problems told to authors by private message or report, which aren't visible in the bot's
+    /// comments.
+    pub private_deliveries: Vec<PrivateDelivery>,
+    /// Replies the bot started posting but doesn't know the outcome of yet.
+    pub intents: Vec<Intent>,
+    /// Replies and edits reddit rate limited, to send again once the wait is over.
+    pub outbox: Vec<OutboxItem>,
+    /// Replies posted in the last day, for the rate caps.
+    pub reply_log: crate::ratecap::ReplyLog,
+}
+
+/// The newest comment processed. Polling resumes from here after a restart.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LastComment {
+    pub name: String,
+    pub created_utc: f64,
+}
+
+/// A flagged comment held so its author has time to fix it.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Pending {
+    pub name: String,
+    /// When to check it again, in seconds since the epoch.
+    pub due_utc: f64,
+}
+
+/// A problematic comment the bot told its author or moderators about privately.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PrivateDelivery {
+    pub parent_name: String,
+    pub author: String,
+    pub permalink: String,
+    pub delivery: crate::config::Delivery,
+    pub created_utc: f64,
+}
+
+/// A reply about to be posted. It's saved before posting so a crash or failed request never
+/// leads to a second reply under the same parent.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Intent {
+    pub parent_name: String,
+    /// The thread, if known, to look for the reply in.
+    pub link_id: Option<String>,
+    pub created_utc: f64,
+}
+
+/// A reply or edit waiting to be sent.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct OutboxItem {
+    pub kind: OutboxKind,
+    pub text: String,
+    /// When to send it, in seconds since the epoch.
+    pub not_before_utc: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum OutboxKind {
+    Reply {
+        parent_name: String,
+        link_id: Option<String>,
+    },
+    Edit {
+        name: String,
+    },
+}
+
+impl State {
+    /// Load the state from a file. A missing file is an empty state.
+    pub fn load(path: &str) -> Result<State, Error> {
+        match std::fs::File::open(path) {
+            Ok(f) => Ok(serde_json::from_reader(std::io::BufReader::new(f))?),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    /// Save the state to a file. The file is replaced atomically so a crash can't truncate it.
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let tmp = format!("{}.tmp", path);
+        {
+            let f = std::fs::File::create(&tmp)?;
+            let mut w = std::io::BufWriter::new(&f);
+            serde_json::to_writer(&mut w, self)?;
+            std::io::Write::flush(&mut w)?;
+            f.sync_all()?;
+        }
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}